@@ -0,0 +1,175 @@
+//! Converting a library downloaded by spotify-dl to another format, without downloading it
+//! again.
+//!
+//! The files are decoded and encoded again into a mirror of the library, with the same tags.
+//! Converting from a lossy format loses quality again, so it's meant for FLAC libraries.
+//! Chapters of single-file albums aren't carried over.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures::StreamExt;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
+
+use crate::decoder::decode_file;
+use crate::encoder::Format;
+use crate::encoder::get_encoder;
+use crate::encoder::tags::Tags;
+use crate::encoder::tags::read_extra_tags;
+use crate::encoder::tags::store_tags;
+use crate::shutdown::Shutdown;
+use crate::utils::partial_path;
+use crate::verify::find_audio_files;
+
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    pub format: Format,
+    pub parallel: usize,
+    /// Convert again the files whose output already exists.
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConvertStatus {
+    Converted,
+    /// Already in the wanted format, so copied as is.
+    Copied,
+    /// The output already exists.
+    Skipped,
+    Failed,
+}
+
+/// The result of converting a single file of the library.
+#[derive(Debug, Clone)]
+pub struct ConvertedFile {
+    pub path: PathBuf,
+    pub output: PathBuf,
+    pub status: ConvertStatus,
+    pub reason: Option<String>,
+}
+
+/// Converts every downloaded file under `source` into the same place under `destination`.
+pub async fn convert_library(
+    source: &Path,
+    destination: &Path,
+    options: &ConvertOptions,
+    shutdown: &Shutdown,
+) -> Result<Vec<ConvertedFile>> {
+    let mut files = Vec::new();
+    find_audio_files(source, &mut files)?;
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let pb = ProgressBar::new(files.len() as u64);
+    pb.enable_steady_tick(Duration::from_millis(100));
+    // Infallible
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} Converting [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta})",
+        )
+        .unwrap()
+        .progress_chars("#>-"),
+    );
+
+    let mut converted: Vec<ConvertedFile> = futures::stream::iter(files)
+        .take_while(|_| futures::future::ready(!shutdown.is_stopped()))
+        .map(|(path, format)| {
+            let pb = pb.clone();
+            async move {
+                let relative = path.strip_prefix(source).unwrap_or(&path);
+                let output = destination
+                    .join(relative)
+                    .with_extension(options.format.extension());
+                let converted = convert_file(&path, format, &output, options, shutdown).await;
+                pb.inc(1);
+                match converted {
+                    Ok(status) => ConvertedFile {
+                        path,
+                        output,
+                        status,
+                        reason: None,
+                    },
+                    Err(e) => {
+                        tracing::warn!("Failed to convert {}: {:?}", path.display(), e);
+                        ConvertedFile {
+                            path,
+                            output,
+                            status: ConvertStatus::Failed,
+                            reason: Some(e.to_string()),
+                        }
+                    }
+                }
+            }
+        })
+        .buffer_unordered(options.parallel)
+        .collect()
+        .await;
+    pb.finish_and_clear();
+
+    converted.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(converted)
+}
+
+async fn convert_file(
+    path: &Path,
+    format: Format,
+    output: &Path,
+    options: &ConvertOptions,
+    shutdown: &Shutdown,
+) -> Result<ConvertStatus> {
+    if output.exists() && !options.force {
+        return Ok(ConvertStatus::Skipped);
+    }
+    if let Some(parent) = output.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    if format == options.format {
+        tokio::fs::copy(path, output).await?;
+        return Ok(ConvertStatus::Copied);
+    }
+
+    let mut tags = read_tags(path, format)?;
+    let decoded = path.to_path_buf();
+    let samples = tokio::task::spawn_blocking(move || decode_file(&decoded, format)).await??;
+    let stream = get_encoder(options.format)
+        .encode(samples, &shutdown.abort_token())
+        .await?;
+    tags.gapless = stream.gapless;
+
+    // Like downloads, only renamed once complete
+    let part_path = partial_path(output);
+    let written = write_file(&stream.stream, &part_path, &tags, options.format).await;
+    match written {
+        Ok(()) => tokio::fs::rename(&part_path, output).await?,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&part_path).await;
+            return Err(e);
+        }
+    }
+    tracing::info!("Converted {} to {}", path.display(), output.display());
+    Ok(ConvertStatus::Converted)
+}
+
+async fn write_file(stream: &[u8], path: &Path, tags: &Tags, format: Format) -> Result<()> {
+    tokio::fs::write(path, stream).await?;
+    store_tags(path.to_string_lossy().to_string(), tags, format).await
+}
+
+/// The tags of a downloaded file, to write them to the converted one.
+fn read_tags(path: &Path, format: Format) -> Result<Tags> {
+    let tag = audiotags::Tag::new().read_from_path(path)?;
+    Ok(Tags {
+        title: tag.title().unwrap_or_default().to_string(),
+        artists: tag.artist().map(str::to_string).into_iter().collect(),
+        album_title: tag.album_title().unwrap_or_default().to_string(),
+        album_cover: tag
+            .album_cover()
+            .map(|cover| Bytes::copy_from_slice(cover.data)),
+        extra: read_extra_tags(path, format)?,
+        gapless: None,
+        chapters: Vec::new(),
+    })
+}
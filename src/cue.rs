@@ -0,0 +1,118 @@
+//! CUE sheets, which tell players where each track starts in a file holding a whole album.
+
+use std::fmt::Write;
+
+use crate::encoder::Format;
+use crate::stream::progress::SAMPLES_PER_SECOND;
+
+/// CUE sheets count time in frames of 1/75th of a second, as on a CD.
+const FRAMES_PER_SECOND: u64 = 75;
+
+#[derive(Debug, Clone)]
+pub struct CueSheet {
+    pub performer: String,
+    pub title: String,
+    /// The name of the audio file, relative to the CUE sheet.
+    pub file: String,
+    pub format: Format,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CueTrack {
+    pub title: String,
+    pub performer: String,
+    /// Where the track starts in the file, in interleaved samples.
+    pub start: u64,
+}
+
+impl CueSheet {
+    pub fn render(&self) -> String {
+        let file_type = match self.format {
+            Format::Flac => "WAVE",
+            Format::Mp3 => "MP3",
+        };
+
+        let mut cue = String::new();
+        // Writing to a String can't fail
+        writeln!(cue, "PERFORMER {}", quote(&self.performer)).unwrap();
+        writeln!(cue, "TITLE {}", quote(&self.title)).unwrap();
+        writeln!(cue, "FILE {} {}", quote(&self.file), file_type).unwrap();
+        for (number, track) in self.tracks.iter().enumerate() {
+            writeln!(cue, "  TRACK {:02} AUDIO", number + 1).unwrap();
+            writeln!(cue, "    TITLE {}", quote(&track.title)).unwrap();
+            writeln!(cue, "    PERFORMER {}", quote(&track.performer)).unwrap();
+            writeln!(cue, "    INDEX 01 {}", timestamp(track.start)).unwrap();
+        }
+        cue
+    }
+}
+
+/// CUE sheets have no way of escaping quotes, so they are swapped for single ones.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "'"))
+}
+
+/// A position as MM:SS:FF, minutes go past 59 for long albums.
+fn timestamp(samples: u64) -> String {
+    let frames = samples * FRAMES_PER_SECOND / SAMPLES_PER_SECOND;
+    let seconds = frames / FRAMES_PER_SECOND;
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 60,
+        seconds % 60,
+        frames % FRAMES_PER_SECOND
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_in_cd_frames() {
+        assert_eq!(timestamp(0), "00:00:00");
+        assert_eq!(timestamp(SAMPLES_PER_SECOND), "00:01:00");
+        // Half a second is 37.5 frames, rounded down
+        assert_eq!(timestamp(SAMPLES_PER_SECOND / 2), "00:00:37");
+        assert_eq!(timestamp(SAMPLES_PER_SECOND * 61 + 1), "01:01:00");
+        assert_eq!(timestamp(SAMPLES_PER_SECOND * 100 * 60), "100:00:00");
+    }
+
+    #[test]
+    fn renders_the_sheet() {
+        let sheet = CueSheet {
+            performer: "The Band".to_string(),
+            title: "An \"Album\"".to_string(),
+            file: "The Band - An Album.flac".to_string(),
+            format: Format::Flac,
+            tracks: vec![
+                CueTrack {
+                    title: "First".to_string(),
+                    performer: "The Band".to_string(),
+                    start: 0,
+                },
+                CueTrack {
+                    title: "Second".to_string(),
+                    performer: "The Band, Someone".to_string(),
+                    start: SAMPLES_PER_SECOND * 185 + SAMPLES_PER_SECOND / 3,
+                },
+            ],
+        };
+
+        assert_eq!(
+            sheet.render(),
+            "PERFORMER \"The Band\"\n\
+             TITLE \"An 'Album'\"\n\
+             FILE \"The Band - An Album.flac\" WAVE\n  \
+             TRACK 01 AUDIO\n    \
+             TITLE \"First\"\n    \
+             PERFORMER \"The Band\"\n    \
+             INDEX 01 00:00:00\n  \
+             TRACK 02 AUDIO\n    \
+             TITLE \"Second\"\n    \
+             PERFORMER \"The Band, Someone\"\n    \
+             INDEX 01 03:05:25\n"
+        );
+    }
+}
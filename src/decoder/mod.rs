@@ -0,0 +1,122 @@
+//! Decoding downloaded files back to [`Samples`], to encode them again in another format.
+
+use std::path::Path;
+
+use anyhow::Result;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::encoder::Format;
+use crate::encoder::Samples;
+
+/// Decodes the audio of a file, which can take a while for a long track.
+pub fn decode_file(path: &Path, format: Format) -> Result<Samples> {
+    let file = std::fs::File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(format.extension());
+    let mut probed = symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions {
+            // Leaves out the encoder delay and padding of MP3, which the encoder adds again
+            enable_gapless: true,
+            ..Default::default()
+        },
+        &MetadataOptions::default(),
+    )?;
+
+    let track = probed.format.default_track().ok_or(anyhow::anyhow!(
+        "No audio stream found in {}",
+        path.display()
+    ))?;
+    let track_id = track.id;
+    let frames = track.codec_params.n_frames;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut samples = Vec::new();
+    let mut sample_rate = 0;
+    let mut channels = 0;
+    loop {
+        let packet = match probed.format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A damaged frame is skipped, like players do
+            Err(SymphoniaError::DecodeError(e)) => {
+                tracing::warn!("Skipping a frame of {}: {}", path.display(), e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let spec = *decoded.spec();
+        sample_rate = spec.rate;
+        channels = spec.channels.count() as u32;
+        // Scaled to the full range of i32, whatever the bit depth of the file
+        let mut buffer = SampleBuffer::<i32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+        samples.extend_from_slice(buffer.samples());
+    }
+
+    if channels == 0 {
+        return Err(anyhow::anyhow!("No audio in {}", path.display()));
+    }
+    // The last block can be padded, the header tells how long the audio really is
+    if let Some(frames) = frames {
+        samples.truncate(frames as usize * channels as usize);
+    }
+    Ok(Samples::new(samples, sample_rate, channels, 32))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::encoder::get_encoder;
+
+    #[tokio::test]
+    async fn flac_round_trip() {
+        // A second and a half of a stereo sine, which doesn't end on a block boundary
+        let samples: Vec<i32> = (0..66150)
+            .flat_map(|i| {
+                let sample = ((i as f64 * 440.0 * std::f64::consts::TAU / 44100.0).sin()
+                    * i32::MAX as f64
+                    * 0.5) as i32;
+                [sample, -sample]
+            })
+            .collect();
+        let encoded = get_encoder(Format::Flac)
+            .encode(
+                Samples::new(samples.clone(), 44100, 2, 32),
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("track.flac");
+        std::fs::write(&path, &encoded.stream).unwrap();
+
+        let decoded = decode_file(&path, Format::Flac).unwrap();
+
+        assert_eq!(decoded.sample_rate, 44100);
+        assert_eq!(decoded.channels, 2);
+        // FLAC keeps 24 bits of every sample
+        let expected: Vec<i32> = samples.iter().map(|sample| sample >> 8 << 8).collect();
+        assert!(decoded.samples == expected);
+    }
+}
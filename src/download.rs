@@ -1,5 +1,9 @@
+use std::collections::HashMap;
 use std::fmt::Write;
+use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
 use std::time::Duration;
 
 use anyhow::Result;
@@ -7,22 +11,119 @@ use futures::StreamExt;
 use futures::TryStreamExt;
 use indicatif::MultiProgress;
 use indicatif::ProgressBar;
+use indicatif::ProgressDrawTarget;
 use indicatif::ProgressState;
 use indicatif::ProgressStyle;
 use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
 use crate::encoder;
 use crate::encoder::Format;
 use crate::encoder::Samples;
+use crate::encoder::tags::Tags;
+use crate::hooks::Hooks;
+use crate::plan::PlannedAction;
+use crate::plan::PlannedTrack;
+use crate::report::DownloadReport;
+use crate::report::TrackReport;
+use crate::report::TrackStatus;
+use crate::retry::RetryPolicy;
+use crate::shutdown::Cancelled;
+use crate::shutdown::Shutdown;
+use crate::space::SpaceCheck;
+use crate::space::SpaceMonitor;
+use crate::stream::DirectStream;
+use crate::stream::Quality;
+use crate::stream::SourceQuality;
 use crate::stream::Stream;
+use crate::stream::StreamError;
 use crate::stream::StreamEvent;
 use crate::stream::StreamEventChannel;
+use crate::stream::pool::PlayerPool;
+use crate::stream::Timeouts;
+use crate::stream::progress::duration_for_samples;
+use crate::throttle::Throttle;
+use crate::track::Availability;
 use crate::track::Track;
+use crate::track::TrackGroup;
 use crate::track::TrackMetadata;
+use crate::utils::format_duration_ms;
+use crate::utils::partial_path;
+use crate::verify::EXPECTED_DURATION_TAG;
+use crate::verify::Incomplete;
+use crate::verify::check_length;
+
+mod album;
+mod preflight;
+
+/// The tags holding the quality of the file the track was downloaded from.
+pub const SOURCE_CODEC_TAG: &str = "SOURCE_CODEC";
+pub const SOURCE_BITRATE_TAG: &str = "SOURCE_BITRATE";
+
+/// The tags of a file holding a single track, other than the ones about how it was downloaded.
+pub(crate) async fn track_tags(metadata: &TrackMetadata) -> Result<Tags> {
+    let mut tags = metadata.tags().await?;
+    // Lets `retag` and other tools find the track again later on
+    tags.extra.extend(metadata.spotify_ids().to_tags());
+    // Lets `verify` check the file later on
+    tags.extra.push((
+        EXPECTED_DURATION_TAG.to_string(),
+        metadata.duration.max(0).to_string(),
+    ));
+    Ok(tags)
+}
+
+/// A format to write the tracks in besides the main one, and where.
+#[derive(Debug, Clone)]
+pub struct ExtraOutput {
+    pub format: Format,
+    pub destination: PathBuf,
+}
+
+impl FromStr for ExtraOutput {
+    type Err = anyhow::Error;
+
+    /// Parses `<format>=<destination>`, e.g. `mp3=/music/mp3`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (format, destination) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <format>=<destination>"))?;
+        Ok(ExtraOutput {
+            format: format.trim().parse()?,
+            destination: PathBuf::from(destination),
+        })
+    }
+}
+
+/// A file a track or an album is written to.
+#[derive(Debug, Clone)]
+pub struct OutputFile {
+    pub format: Format,
+    pub path: PathBuf,
+}
+
+impl OutputFile {
+    /// Where the file is written until it is complete.
+    fn partial(&self) -> OutputFile {
+        OutputFile {
+            format: self.format,
+            path: partial_path(&self.path),
+        }
+    }
+}
 
 pub struct Downloader {
     session: Session,
     progress_bar: MultiProgress,
+    shutdown: Shutdown,
+    players: PlayerPool,
+    finished: Option<UnboundedSender<TrackReport>>,
+    space: SpaceMonitor,
+    /// Fetched before the download, taken by the tracks as they start.
+    metadata: Mutex<HashMap<SpotifyId, TrackMetadata>>,
 }
 
 #[derive(Debug, Clone)]
@@ -30,7 +131,25 @@ pub struct DownloadOptions {
     pub destination: PathBuf,
     pub parallel: usize,
     pub format: Format,
+    /// Formats to write every track in as well, from the same stream.
+    pub extra_outputs: Vec<ExtraOutput>,
     pub force: bool,
+    pub use_alternatives: bool,
+    pub retry: RetryPolicy,
+    /// Shared by the whole download, and by `retry` for the requests it sends.
+    pub throttle: Throttle,
+    pub direct: bool,
+    pub quality: Quality,
+    pub timeouts: Timeouts,
+    /// How much shorter than its duration a track can be before it is streamed again.
+    pub length_tolerance: Duration,
+    pub hooks: Hooks,
+    /// Download albums as a single file with a CUE sheet.
+    pub single_file: bool,
+    pub space_check: SpaceCheck,
+    /// Name the files of tracks after their id instead of their artists and title, so tracks
+    /// that are named alike don't end up in the same file.
+    pub name_by_id: bool,
 }
 
 impl DownloadOptions {
@@ -41,7 +160,86 @@ impl DownloadOptions {
             destination,
             parallel,
             format,
+            extra_outputs: Vec::new(),
             force,
+            use_alternatives: false,
+            retry: RetryPolicy::default(),
+            throttle: Throttle::default(),
+            direct: false,
+            quality: Quality::default(),
+            timeouts: Timeouts::default(),
+            length_tolerance: crate::verify::DEFAULT_TOLERANCE,
+            hooks: Hooks::default(),
+            single_file: false,
+            space_check: SpaceCheck::default(),
+            name_by_id: false,
+        }
+    }
+
+    pub fn output_path(&self, metadata: &TrackMetadata) -> PathBuf {
+        self.destination
+            .join(self.file_name(metadata))
+            .with_extension(self.format.extension())
+    }
+
+    /// Where the track goes in every format, starting with the main one.
+    pub fn output_paths(&self, metadata: &TrackMetadata) -> Vec<OutputFile> {
+        self.output_files(&self.file_name(metadata))
+    }
+
+    fn file_name(&self, metadata: &TrackMetadata) -> String {
+        match metadata.id.to_base62() {
+            Ok(id) if self.name_by_id => id,
+            _ => metadata.to_string(),
+        }
+    }
+
+    /// The file named `name` in every format, each in its destination.
+    fn output_files(&self, name: &str) -> Vec<OutputFile> {
+        let main = (self.format, &self.destination);
+        let extra = self
+            .extra_outputs
+            .iter()
+            .map(|output| (output.format, &output.destination));
+        std::iter::once(main)
+            .chain(extra)
+            .map(|(format, destination)| OutputFile {
+                format,
+                path: destination.join(name).with_extension(format.extension()),
+            })
+            .collect()
+    }
+
+    /// The files that have to be written, all of them when forced.
+    fn pending(&self, files: Vec<OutputFile>) -> Vec<OutputFile> {
+        files
+            .into_iter()
+            .filter(|file| self.force || !file.path.exists())
+            .collect()
+    }
+
+    /// Picks the track to stream given its availability, or the reason why it can't be downloaded.
+    fn playable_track(
+        &self,
+        track: Track,
+        availability: Availability,
+    ) -> std::result::Result<Track, String> {
+        match availability {
+            Availability::Available => Ok(track),
+            Availability::Relinked(id) => Ok(Track::from_id(id)),
+            Availability::Alternative { id, reason } if self.use_alternatives => {
+                tracing::info!(
+                    "Substituting unavailable track {:?} ({}) with {:?}",
+                    track.id,
+                    reason,
+                    id
+                );
+                Ok(Track::from_id(id))
+            }
+            Availability::Alternative { reason, .. } => {
+                Err(format!("{} (an alternative version is available)", reason))
+            }
+            Availability::Unavailable(reason) => Err(reason),
         }
     }
 }
@@ -49,95 +247,504 @@ impl DownloadOptions {
 impl Downloader {
     pub fn new(session: Session) -> Self {
         Downloader {
+            players: PlayerPool::new(session.clone()),
             session,
             progress_bar: MultiProgress::new(),
+            shutdown: Shutdown::new(),
+            finished: None,
+            space: SpaceMonitor::default(),
+            metadata: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Lets `shutdown` stop the download run early.
+    pub fn with_shutdown(self, shutdown: Shutdown) -> Self {
+        Downloader { shutdown, ..self }
+    }
+
+    /// Sends the report of every track to `finished` as soon as the track is done.
+    pub fn with_finished_tracks(self, finished: UnboundedSender<TrackReport>) -> Self {
+        Downloader {
+            finished: Some(finished),
+            ..self
+        }
+    }
+
+    /// Uses the metadata the caller already has instead of fetching it again.
+    pub fn with_metadata(self, metadata: impl IntoIterator<Item = TrackMetadata>) -> Self {
+        self.metadata
+            .lock()
+            .unwrap()
+            .extend(metadata.into_iter().map(|metadata| (metadata.id, metadata)));
+        self
+    }
+
+    /// Doesn't draw any progress bars, for when nobody is watching the terminal.
+    pub fn without_progress_bars(self) -> Self {
+        self.progress_bar.set_draw_target(ProgressDrawTarget::hidden());
+        self
+    }
+
+    /// Downloads the tracks of every input as `options` say, then runs `--on-complete`.
+    pub async fn download(
+        self,
+        groups: Vec<TrackGroup>,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport> {
+        self.check_space(&groups, options).await?;
+
+        let shutdown = self.shutdown.clone();
+        let report = if options.single_file {
+            self.download_albums(groups, options).await?
+        } else {
+            let tracks = groups.into_iter().flat_map(|group| group.tracks).collect();
+            self.download_tracks(tracks, options).await?
+        };
+
+        if shutdown.is_aborted() {
+            return Ok(report);
         }
+        let on_complete = options.hooks.complete(&report).await;
+        Ok(report.with_on_complete(on_complete))
     }
 
+    /// Downloads the tracks, a stop lets the tracks in progress finish but doesn't start
+    /// the rest of them.
     pub async fn download_tracks(
         self,
         tracks: Vec<Track>,
         options: &DownloadOptions,
-    ) -> Result<()> {
+    ) -> Result<DownloadReport> {
+        let total = tracks.len();
+        let tracks = self.download_each(tracks, options).await;
+
+        let not_started = total - tracks.len();
+        Ok(DownloadReport::new(tracks).with_not_started(not_started))
+    }
+
+    /// Downloads every album as a single file with a CUE sheet, and the other tracks as usual.
+    pub async fn download_albums(
+        self,
+        groups: Vec<TrackGroup>,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport> {
+        let total = groups.iter().map(|group| group.tracks.len()).sum::<usize>();
+        let (albums, singles): (Vec<_>, Vec<_>) =
+            groups.into_iter().partition(|group| group.album);
+
+        let mut tracks = Vec::new();
+        for album in albums {
+            if self.shutdown.is_stopped() {
+                break;
+            }
+            let reports = self.download_album(album.tracks, options).await;
+            reports.iter().for_each(|report| self.track_finished(report));
+            tracks.extend(reports);
+        }
+        let singles = singles.into_iter().flat_map(|group| group.tracks).collect();
+        tracks.extend(self.download_each(singles, options).await);
+
+        let not_started = total - tracks.len();
+        Ok(DownloadReport::new(tracks).with_not_started(not_started))
+    }
+
+    /// Downloads the tracks, every one of them ends up in the reports, even when it fails.
+    async fn download_each(
+        &self,
+        tracks: Vec<Track>,
+        options: &DownloadOptions,
+    ) -> Vec<TrackReport> {
         futures::stream::iter(tracks)
+            .take_while(|_| futures::future::ready(!self.shutdown.is_stopped()))
             .map(|track| self.download_track(track, options))
             .buffer_unordered(options.parallel)
-            .try_collect::<Vec<_>>()
-            .await?;
+            .inspect(|report| self.track_finished(report))
+            .collect()
+            .await
+    }
 
-        Ok(())
+    fn track_finished(&self, report: &TrackReport) {
+        if let Some(finished) = &self.finished {
+            // Nobody listening anymore is fine
+            let _ = finished.send(report.clone());
+        }
+    }
+
+    /// Resolves what `download_tracks` would do with each track, without streaming anything.
+    pub async fn plan_tracks(
+        &self,
+        tracks: Vec<Track>,
+        options: &DownloadOptions,
+    ) -> Result<Vec<PlannedTrack>> {
+        futures::stream::iter(tracks)
+            .map(|track| self.plan_track(track, options))
+            .buffered(options.parallel)
+            .try_collect()
+            .await
+    }
+
+    async fn plan_track(&self, track: Track, options: &DownloadOptions) -> Result<PlannedTrack> {
+        let metadata = track.metadata(&self.session, &options.retry).await?;
+        let availability = track.availability(&self.session, &options.retry).await?;
+        let path = options.output_path(&metadata);
+        let pending = options.pending(options.output_paths(&metadata));
+        let uri = track.id.to_uri()?;
+
+        let (action, reason) = match options.playable_track(track, availability) {
+            Err(reason) => (PlannedAction::Unavailable, Some(reason)),
+            Ok(_) if pending.is_empty() => {
+                (PlannedAction::Skip, Some("file already exists".to_string()))
+            }
+            Ok(playable) if playable.id != metadata.id => (
+                PlannedAction::Download,
+                Some(format!("substituted with {}", playable.id.to_uri()?)),
+            ),
+            Ok(_) => (PlannedAction::Download, None),
+        };
+
+        Ok(PlannedTrack {
+            uri,
+            name: metadata.to_string(),
+            path,
+            duration_ms: metadata.duration,
+            available: action != PlannedAction::Unavailable,
+            reason,
+            action,
+        })
     }
 
     #[tracing::instrument(name = "download_track", skip(self))]
-    async fn download_track(&self, track: Track, options: &DownloadOptions) -> Result<()> {
-        let metadata = track.metadata(&self.session).await?;
+    async fn download_track(&self, track: Track, options: &DownloadOptions) -> TrackReport {
+        let metadata = match self.track_metadata(&track, options).await {
+            Ok(metadata) => metadata,
+            Err(e) => return self.lookup_failed(&track, e),
+        };
+        let report = self
+            .download_resolved_track(track, &metadata, options)
+            .await;
+        self.run_track_hook(&metadata, report, options).await
+    }
+
+    /// Reports a track whose metadata couldn't be fetched, named after its URI.
+    fn lookup_failed(&self, track: &Track, e: anyhow::Error) -> TrackReport {
+        let uri = track.id.to_uri().unwrap_or_default();
+        self.fail_before_start(&uri, &e);
+        TrackReport::unresolved(uri, TrackStatus::Failed).with_reason(e.to_string())
+    }
+
+    /// Runs `--on-track-complete` for the track, unless the download was aborted.
+    async fn run_track_hook(
+        &self,
+        metadata: &TrackMetadata,
+        report: TrackReport,
+        options: &DownloadOptions,
+    ) -> TrackReport {
+        if self.shutdown.is_aborted() {
+            return report;
+        }
+        options
+            .hooks
+            .track_complete(metadata, report, options.format)
+            .await
+    }
+
+    async fn download_resolved_track(
+        &self,
+        track: Track,
+        metadata: &TrackMetadata,
+        options: &DownloadOptions,
+    ) -> TrackReport {
         tracing::info!("Downloading track: {:?}", metadata.track_name);
 
-        let path = options
-            .destination
-            .join(metadata.to_string())
-            .with_extension(options.format.extension())
-            .to_str()
-            .ok_or(anyhow::anyhow!("Could not set the output path"))?
-            .to_string();
+        let output_path = options.output_path(metadata);
+        let report = |status| TrackReport::new(metadata, output_path.clone(), status);
 
-        if !options.force && PathBuf::from(&path).exists() {
+        let pending = options.pending(options.output_paths(metadata));
+        if pending.is_empty() {
             tracing::info!(
                 "Skipping {}, file already exists. Use --force to force re-downloading the track",
                 &metadata.track_name
             );
-            return Ok(());
+            return report(TrackStatus::Skipped).with_reason("file already exists");
         }
 
-        let pb = self.add_progress_bar(&metadata);
-
-        let stream = Stream::new(self.session.clone());
-        let channel = match stream.stream(track).await {
-            Ok(channel) => channel,
+        let availability = match track.availability(&self.session, &options.retry).await {
+            Ok(availability) => availability,
             Err(e) => {
-                self.fail_with_error(&pb, &metadata.to_string(), e.to_string());
-                return Ok(());
+                self.fail_before_start(&metadata.to_string(), &e);
+                return report(TrackStatus::Failed).with_reason(e.to_string());
             }
         };
+        let track = match options.playable_track(track, availability) {
+            Ok(track) => track,
+            Err(reason) => {
+                self.skip_with_reason(metadata, &reason);
+                return report(TrackStatus::Unavailable).with_reason(reason);
+            }
+        };
+
+        options.throttle.pause_between_tracks().await;
+        let duration = metadata.duration.max(0) as u64;
+        let _space = self
+            .wait_for_space(&metadata.to_string(), &pending, duration, options)
+            .await;
+        if self.shutdown.is_stopped() {
+            tracing::info!("Not starting {}, the download was stopped", metadata);
+            return report(TrackStatus::Cancelled).with_reason("stopped before it started");
+        }
+        let pb = self.add_progress_bar(metadata);
+
+        // Written next to the final paths and only renamed once complete, so an interrupted
+        // download never leaves a truncated file that looks finished
+        let parts: Vec<OutputFile> = pending.iter().map(OutputFile::partial).collect();
+        let abort = self.shutdown.abort_token();
+        let written = tokio::select! {
+            written = self.write_track(track, metadata, &parts, &pb, options) => written,
+            _ = abort.cancelled() => Err(Cancelled.into()),
+        };
+        let written = match written {
+            Ok(source) => Self::finish_files(&parts, &pending).await.map(|_| source),
+            Err(e) => Err(e),
+        };
 
-        let samples = match self.buffer_track(channel, &pb, &metadata).await {
-            Ok(samples) => samples,
+        match written {
+            Ok(source) => {
+                pb.finish_with_message(format!("Downloaded {}", metadata));
+                report(TrackStatus::Downloaded).with_source(source)
+            }
             Err(e) => {
+                Self::remove_partial_files(&parts).await;
+                if e.is::<Cancelled>() || self.shutdown.is_aborted() {
+                    tracing::warn!("Aborted {}", metadata);
+                    pb.abandon_with_message(
+                        console::style(format!("Cancelled! {}", metadata))
+                            .yellow()
+                            .to_string(),
+                    );
+                    return report(TrackStatus::Cancelled).with_reason("aborted");
+                }
                 self.fail_with_error(&pb, &metadata.to_string(), e.to_string());
-                return Ok(());
+                report(TrackStatus::Failed).with_reason(e.to_string())
             }
+        }
+    }
+
+    /// Streams the track once, then encodes and tags it into every one of `files`, returning
+    /// the quality of its source.
+    async fn write_track(
+        &self,
+        track: Track,
+        metadata: &TrackMetadata,
+        files: &[OutputFile],
+        pb: &ProgressBar,
+        options: &DownloadOptions,
+    ) -> Result<Option<SourceQuality>> {
+        let (samples, source) = self.stream_track(track, metadata, pb, options).await?;
+
+        let mut tags = track_tags(metadata).await?;
+        if let Some(source) = source {
+            tags.extra.extend([
+                (SOURCE_CODEC_TAG.to_string(), source.codec().to_string()),
+                (SOURCE_BITRATE_TAG.to_string(), source.kbps().to_string()),
+            ]);
+        }
+        self.write_files(samples, tags, &metadata.to_string(), files, pb)
+            .await?;
+
+        Ok(source)
+    }
+
+    /// Streams the whole track, streaming it again if it stalls or comes out truncated.
+    async fn stream_track(
+        &self,
+        track: Track,
+        metadata: &TrackMetadata,
+        pb: &ProgressBar,
+        options: &DownloadOptions,
+    ) -> Result<(Samples, Option<SourceQuality>)> {
+        let cancel = self.shutdown.abort_token();
+        let max_retries = options.retry.max_retries;
+        let mut attempts = 0;
+        let (samples, source) = loop {
+            // Cancelled once the attempt is over, so a stuck stream doesn't keep running
+            let attempt = cancel.child_token();
+            let _stop_stream = attempt.clone().drop_guard();
+            let channel = self.open_stream(track.clone(), attempt, options).await?;
+
+            let buffered = self
+                .buffer_track(channel, pb, metadata, &options.timeouts)
+                .await
+                .and_then(|(samples, source)| {
+                    let length = duration_for_samples(samples.samples.len() as u64);
+                    check_length(
+                        metadata.duration.max(0) as u64,
+                        length.as_millis() as u64,
+                        options.length_tolerance,
+                    )?;
+                    Ok((samples, source))
+                });
+            let e = match buffered {
+                Ok(buffered) => break buffered,
+                Err(e) => e,
+            };
+            let Some(problem) = Self::stream_problem(&e).filter(|_| attempts < max_retries) else {
+                return Err(e);
+            };
+
+            attempts += 1;
+            tracing::warn!(
+                "Streaming {} failed, retrying ({}/{}): {}",
+                metadata,
+                attempts,
+                max_retries,
+                e
+            );
+            pb.set_message(format!(
+                "{}, retrying ({}/{}) {}",
+                problem, attempts, max_retries, metadata
+            ));
+            pb.set_position(0);
         };
+        Ok((samples, source))
+    }
 
-        tracing::info!("Encoding track: {}", metadata.to_string());
-        pb.set_message(format!("Encoding {}", metadata.to_string()));
+    /// Encodes the samples into every one of `files` at the same time, and tags them.
+    async fn write_files(
+        &self,
+        samples: Samples,
+        tags: Tags,
+        name: &str,
+        files: &[OutputFile],
+        pb: &ProgressBar,
+    ) -> Result<()> {
+        tracing::info!("Encoding track: {}", name);
+        pb.set_message(format!("Encoding {}", name));
 
-        let encoder = crate::encoder::get_encoder(options.format);
-        let stream = encoder.encode(samples).await?;
+        // Every encoder takes its own copy, the last one takes the original
+        let mut copies: Vec<Samples> = files.iter().skip(1).map(|_| samples.clone()).collect();
+        copies.push(samples);
+        futures::future::try_join_all(
+            files
+                .iter()
+                .zip(copies)
+                .map(|(file, samples)| self.write_file(samples, tags.clone(), name, file, pb)),
+        )
+        .await?;
+        Ok(())
+    }
 
-        pb.set_message(format!("Writing {}", metadata.to_string()));
-        tracing::info!(
-            "Writing track: {:?} to file: {}",
-            metadata.to_string(),
-            &path
-        );
-        stream.write_to_file(&path).await?;
+    /// Encodes the samples into `file` and tags it.
+    async fn write_file(
+        &self,
+        samples: Samples,
+        mut tags: Tags,
+        name: &str,
+        file: &OutputFile,
+        pb: &ProgressBar,
+    ) -> Result<()> {
+        let encoder = crate::encoder::get_encoder(file.format);
+        let stream = encoder
+            .encode(samples, &self.shutdown.abort_token())
+            .await?;
 
-        let tags = metadata.tags().await?;
-        encoder::tags::store_tags(path, &tags, options.format).await?;
+        pb.set_message(format!("Writing {}", name));
+        tracing::info!("Writing track: {:?} to file: {}", name, file.path.display());
+        stream.write_to_file(&file.path).await?;
 
-        pb.finish_with_message(format!("Downloaded {}", metadata.to_string()));
+        tags.gapless = stream.gapless;
+        let path = file
+            .path
+            .to_str()
+            .ok_or(anyhow::anyhow!("Could not set the output path"))?
+            .to_string();
+        encoder::tags::store_tags(path, &tags, file.format).await
+    }
+
+    /// Moves the complete files in place.
+    async fn finish_files(parts: &[OutputFile], files: &[OutputFile]) -> Result<()> {
+        for (part, file) in parts.iter().zip(files) {
+            tokio::fs::rename(&part.path, &file.path).await?;
+        }
         Ok(())
     }
 
+    async fn open_stream(
+        &self,
+        track: Track,
+        cancel: CancellationToken,
+        options: &DownloadOptions,
+    ) -> Result<StreamEventChannel> {
+        let retry = options.retry.clone();
+        let throttle = options.throttle.clone();
+        if options.direct {
+            let session = self.session.clone();
+            DirectStream::new(
+                session,
+                retry,
+                throttle,
+                options.quality,
+                options.timeouts,
+                cancel,
+            )
+            .stream(track)
+            .await
+        } else {
+            let players = self.players.clone();
+            Stream::new(
+                players,
+                retry,
+                throttle,
+                options.quality,
+                options.timeouts,
+                cancel,
+            )
+            .stream(track)
+            .await
+        }
+    }
+
+    /// Problems worth streaming the track again for, with how they are shown on its progress bar.
+    fn stream_problem(e: &anyhow::Error) -> Option<&'static str> {
+        if e.is::<Incomplete>() {
+            Some("Truncated")
+        } else if matches!(e.downcast_ref::<StreamError>(), Some(StreamError::Timeout(_))) {
+            Some("Stalled")
+        } else {
+            None
+        }
+    }
+
+    async fn remove_partial_files(parts: &[OutputFile]) {
+        for part in parts {
+            Self::remove_partial_file(&part.path).await;
+        }
+    }
+
+    async fn remove_partial_file(path: &Path) {
+        if let Err(e) = tokio::fs::remove_file(path).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            tracing::warn!("Failed to remove partial file {}: {}", path.display(), e);
+        }
+    }
+
     fn add_progress_bar(&self, track: &TrackMetadata) -> ProgressBar {
         let pb = self
             .progress_bar
-            .add(ProgressBar::new(track.approx_size() as u64));
+            .add(ProgressBar::new(track.total_samples()));
         pb.enable_steady_tick(Duration::from_millis(100));
-        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {audio_pos}/{audio_len} ({realtime}, {eta})")
             // Infallible
             .unwrap()
             .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
+            .with_key("audio_pos", |state: &ProgressState, w: &mut dyn Write| write!(w, "{}", Self::format_samples(state.pos())).unwrap())
+            .with_key("audio_len", |state: &ProgressState, w: &mut dyn Write| write!(w, "{}", Self::format_samples(state.len().unwrap_or_default())).unwrap())
+            .with_key("realtime", |state: &ProgressState, w: &mut dyn Write| {
+                let audio = duration_for_samples(state.pos()).as_secs_f64();
+                let elapsed = state.elapsed().as_secs_f64();
+                let speed = if elapsed > 0.0 { audio / elapsed } else { 0.0 };
+                write!(w, "{:.1}x", speed).unwrap()
+            })
             .progress_chars("#>-"));
         pb.set_message(track.to_string());
         pb
@@ -148,26 +755,72 @@ impl Downloader {
         mut rx: StreamEventChannel,
         pb: &ProgressBar,
         metadata: &TrackMetadata,
-    ) -> Result<Samples> {
+        timeouts: &Timeouts,
+    ) -> Result<(Samples, Option<SourceQuality>)> {
         let mut samples = Vec::<i32>::new();
-        while let Some(event) = rx.recv().await {
+        let mut source = None;
+        let mut finished = false;
+        let max_duration = timeouts.total(metadata.duration.max(0) as u64);
+        // Armed once the track is loaded, loading has its own timeouts and retries
+        let mut deadline = None;
+        loop {
+            let event = match deadline {
+                None => rx.recv().await,
+                Some(deadline) => {
+                    let stall = Instant::now() + timeouts.stall;
+                    match tokio::time::timeout_at(stall.min(deadline), rx.recv()).await {
+                        Ok(event) => event,
+                        Err(_) if stall < deadline => {
+                            return Err(StreamError::Timeout(format!(
+                                "no audio received for {:?}",
+                                timeouts.stall
+                            ))
+                            .into());
+                        }
+                        Err(_) => {
+                            return Err(StreamError::Timeout(format!(
+                                "streaming took longer than {:?}",
+                                max_duration
+                            ))
+                            .into());
+                        }
+                    }
+                }
+            };
+            let Some(event) = event else {
+                break;
+            };
+            if deadline.is_none()
+                && matches!(event, StreamEvent::Source(_) | StreamEvent::Write { .. })
+            {
+                deadline = Some(Instant::now() + max_duration);
+            }
+
             match event {
                 StreamEvent::Write {
-                    bytes,
+                    position,
                     total,
                     mut content,
                 } => {
-                    tracing::trace!("Written {} bytes out of {}", bytes, total);
-                    pb.set_position(bytes as u64);
+                    tracing::trace!("Written {} samples out of {}", position, total);
+                    pb.set_length(total.max(position));
+                    pb.set_position(position);
                     samples.append(&mut content);
                 }
+                StreamEvent::Source(quality) => {
+                    tracing::info!("Streaming {} from {}", metadata, quality);
+                    source = Some(quality);
+                }
                 StreamEvent::Finished => {
                     tracing::info!("Finished downloading track");
+                    // The metadata duration is rounded, don't leave the bar just short of the end
+                    pb.set_length(pb.position());
+                    finished = true;
                     break;
                 }
                 StreamEvent::Error(stream_error) => {
                     tracing::error!("Error while streaming track: {:?}", stream_error);
-                    return Err(anyhow::anyhow!("Streaming error: {:?}", stream_error));
+                    return Err(stream_error.into());
                 }
                 StreamEvent::Retry {
                     attempt,
@@ -181,17 +834,41 @@ impl Downloader {
                     );
                     pb.set_message(format!(
                         "Retrying ({}/{}) {}",
-                        attempt,
-                        max_attempts,
-                        metadata.to_string()
+                        attempt, max_attempts, metadata
                     ));
                 }
             }
         }
-        Ok(Samples {
+        if !finished {
+            return Err(anyhow::anyhow!("The stream ended before the end of the track"));
+        }
+        let samples = Samples {
             samples,
             ..Default::default()
-        })
+        };
+        Ok((samples, source))
+    }
+
+    fn format_samples(samples: u64) -> String {
+        format_duration_ms(duration_for_samples(samples).as_millis() as u64)
+    }
+
+    fn skip_with_reason(&self, metadata: &TrackMetadata, reason: &str) {
+        tracing::warn!("Skipping {}: {}", metadata, reason);
+        let pb = self.progress_bar.add(ProgressBar::new(0));
+        pb.set_style(ProgressStyle::with_template("{msg}").unwrap());
+        pb.finish_with_message(
+            console::style(format!("Unavailable! {}: {}", metadata, reason))
+                .yellow()
+                .to_string(),
+        );
+    }
+
+    /// Shows that a track failed before it got a progress bar of its own.
+    fn fail_before_start(&self, name: &str, e: &anyhow::Error) {
+        let pb = self.progress_bar.add(ProgressBar::new(0));
+        pb.set_style(ProgressStyle::with_template("{msg}").unwrap());
+        self.fail_with_error(&pb, name, e.to_string());
     }
 
     fn fail_with_error<S>(&self, pb: &ProgressBar, name: &str, e: S)
@@ -0,0 +1,362 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
+
+use crate::cue::CueSheet;
+use crate::cue::CueTrack;
+use crate::encoder::Samples;
+use crate::encoder::tags::Chapter;
+use crate::report::TrackReport;
+use crate::report::TrackStatus;
+use crate::shutdown::Cancelled;
+use crate::stream::SourceQuality;
+use crate::track::AlbumMetadata;
+use crate::track::ArtistMetadata;
+use crate::track::Track;
+use crate::track::TrackMetadata;
+use crate::utils::clean_invalid_characters;
+use crate::verify::EXPECTED_DURATION_TAG;
+
+use super::DownloadOptions;
+use super::Downloader;
+use super::OutputFile;
+
+/// A track of an album, with the track to stream for it or the reason why it can't be.
+struct AlbumTrack {
+    metadata: TrackMetadata,
+    playable: std::result::Result<Track, String>,
+}
+
+impl DownloadOptions {
+    /// Where a whole album goes when it is downloaded as a single file.
+    pub fn album_path(&self, album: &AlbumMetadata) -> PathBuf {
+        self.destination
+            .join(album_file_name(album))
+            .with_extension(self.format.extension())
+    }
+
+    /// Where the album goes in every format, starting with the main one.
+    pub fn album_paths(&self, album: &AlbumMetadata) -> Vec<OutputFile> {
+        self.output_files(&album_file_name(album))
+    }
+}
+
+fn album_file_name(album: &AlbumMetadata) -> String {
+    clean_invalid_characters(format!(
+        "{} - {}",
+        artist_names(&album.artists),
+        album.name
+    ))
+}
+
+/// Where the CUE sheet of each file goes: next to it, or named after the whole file, e.g.
+/// `Album.mp3.cue`, when another format of the album already has its sheet there.
+fn cue_paths(files: &[OutputFile]) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = Vec::with_capacity(files.len());
+    for file in files {
+        let mut path = file.path.with_extension("cue");
+        if paths.contains(&path) {
+            path = file
+                .path
+                .with_extension(format!("{}.cue", file.format.extension()));
+        }
+        paths.push(path);
+    }
+    paths
+}
+
+impl Downloader {
+    /// Downloads the tracks of an album into a single continuous file, with a CUE sheet next to
+    /// it and chapters inside it marking where each track starts. Unavailable tracks are left
+    /// out of the file.
+    ///
+    /// The album is encoded in one go, so there are no gaps between the tracks, but all of its
+    /// audio is kept in memory until then.
+    pub(super) async fn download_album(
+        &self,
+        tracks: Vec<Track>,
+        options: &DownloadOptions,
+    ) -> Vec<TrackReport> {
+        let resolved: Vec<std::result::Result<AlbumTrack, TrackReport>> =
+            futures::stream::iter(tracks)
+                .map(|track| self.resolve_album_track(track, options))
+                .buffered(options.parallel)
+                .collect()
+                .await;
+        // Tracks that couldn't be looked up are reported as failed, the rest of the album
+        // is still downloaded
+        let mut tracks = Vec::with_capacity(resolved.len());
+        let mut failed = Vec::new();
+        for track in resolved {
+            match track {
+                Ok(track) => tracks.push(track),
+                Err(report) => failed.push(report),
+            }
+        }
+
+        let reports = self.download_resolved_album(&tracks, options).await;
+        let mut hooked = Vec::with_capacity(reports.len() + failed.len());
+        for (track, report) in tracks.iter().zip(reports) {
+            hooked.push(self.run_track_hook(&track.metadata, report, options).await);
+        }
+        hooked.extend(failed);
+        hooked
+    }
+
+    async fn download_resolved_album(
+        &self,
+        tracks: &[AlbumTrack],
+        options: &DownloadOptions,
+    ) -> Vec<TrackReport> {
+        let Some(first) = tracks.first() else {
+            return Vec::new();
+        };
+        let album = first.metadata.album.clone();
+        tracing::info!("Downloading album: {:?}", album.name);
+
+        let output_path = options.album_path(&album);
+        let reports = |status, reason: &str| {
+            tracks
+                .iter()
+                .map(|track| {
+                    TrackReport::new(&track.metadata, output_path.clone(), status)
+                        .with_reason(reason)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let files = options.album_paths(&album);
+        let (pending, cue_paths): (Vec<OutputFile>, Vec<PathBuf>) = files
+            .iter()
+            .cloned()
+            .zip(cue_paths(&files))
+            .filter(|(file, _)| options.force || !file.path.exists())
+            .unzip();
+        if pending.is_empty() {
+            tracing::info!(
+                "Skipping {}, file already exists. Use --force to force re-downloading the album",
+                album.name
+            );
+            return reports(TrackStatus::Skipped, "file already exists");
+        }
+        for track in tracks {
+            if let Err(reason) = &track.playable {
+                self.skip_with_reason(&track.metadata, reason);
+            }
+        }
+
+        options.throttle.pause_between_tracks().await;
+        let duration = tracks
+            .iter()
+            .filter(|track| track.playable.is_ok())
+            .map(|track| track.metadata.duration.max(0) as u64)
+            .sum();
+        let _space = self
+            .wait_for_space(&album.name, &pending, duration, options)
+            .await;
+        if self.shutdown.is_stopped() {
+            tracing::info!("Not starting {}, the download was stopped", album.name);
+            return reports(TrackStatus::Cancelled, "stopped before it started");
+        }
+
+        let pb = self.add_album_progress_bar(&album);
+        let parts: Vec<OutputFile> = pending.iter().map(OutputFile::partial).collect();
+        let abort = self.shutdown.abort_token();
+        let written = tokio::select! {
+            written = self.write_album(&album, tracks, &pending, &parts, &pb, options) => written,
+            _ = abort.cancelled() => Err(Cancelled.into()),
+        };
+        let written = match written {
+            Ok((sources, cues)) => Self::finish_album(&parts, &pending, &cues, &cue_paths)
+                .await
+                .map(|_| sources),
+            Err(e) => Err(e),
+        };
+
+        let sources = match written {
+            Ok(sources) => sources,
+            Err(e) => {
+                Self::remove_partial_files(&parts).await;
+                if e.is::<Cancelled>() || self.shutdown.is_aborted() {
+                    tracing::warn!("Aborted {}", album.name);
+                    pb.abandon_with_message(
+                        console::style(format!("Cancelled! {}", album.name))
+                            .yellow()
+                            .to_string(),
+                    );
+                    return reports(TrackStatus::Cancelled, "aborted");
+                }
+                self.fail_with_error(&pb, &album.name, e.to_string());
+                return reports(TrackStatus::Failed, &e.to_string());
+            }
+        };
+        pb.finish_with_message(format!("Downloaded {}", album.name));
+
+        let mut sources = sources.into_iter();
+        tracks
+            .iter()
+            .map(|track| {
+                let report =
+                    |status| TrackReport::new(&track.metadata, output_path.clone(), status);
+                match &track.playable {
+                    Ok(_) => report(TrackStatus::Downloaded).with_source(sources.next().flatten()),
+                    Err(reason) => report(TrackStatus::Unavailable).with_reason(reason.clone()),
+                }
+            })
+            .collect()
+    }
+
+    /// Looks up the track, or reports it as failed when that doesn't work out.
+    async fn resolve_album_track(
+        &self,
+        track: Track,
+        options: &DownloadOptions,
+    ) -> std::result::Result<AlbumTrack, TrackReport> {
+        let metadata = match self.track_metadata(&track, options).await {
+            Ok(metadata) => metadata,
+            Err(e) => return Err(self.lookup_failed(&track, e)),
+        };
+        let availability = match track.availability(&self.session, &options.retry).await {
+            Ok(availability) => availability,
+            Err(e) => {
+                self.fail_before_start(&metadata.to_string(), &e);
+                let path = options.album_path(&metadata.album);
+                return Err(TrackReport::new(&metadata, path, TrackStatus::Failed)
+                    .with_reason(e.to_string()));
+            }
+        };
+        Ok(AlbumTrack {
+            metadata,
+            playable: options.playable_track(track, availability),
+        })
+    }
+
+    /// Streams the playable tracks of the album and encodes them into each of `parts` as a
+    /// single file, returning the quality of the source of each one and the CUE sheet of each
+    /// of `files`.
+    async fn write_album(
+        &self,
+        album: &AlbumMetadata,
+        tracks: &[AlbumTrack],
+        files: &[OutputFile],
+        parts: &[OutputFile],
+        pb: &ProgressBar,
+        options: &DownloadOptions,
+    ) -> Result<(Vec<Option<SourceQuality>>, Vec<CueSheet>)> {
+        let playable: Vec<(Track, &TrackMetadata)> = tracks
+            .iter()
+            .filter_map(|track| Some((track.playable.clone().ok()?, &track.metadata)))
+            .collect();
+        let metadata: Vec<&TrackMetadata> =
+            playable.iter().map(|(_, metadata)| *metadata).collect();
+
+        // Buffered rather than unordered, the tracks have to end up in the order of the album
+        let streamed: Vec<(Samples, Option<SourceQuality>)> = futures::stream::iter(playable)
+            .map(|(track, metadata)| async move {
+                let pb = self.add_progress_bar(metadata);
+                let streamed = self.stream_track(track, metadata, &pb, options).await;
+                match &streamed {
+                    Ok(_) => pb.finish_with_message(format!("Streamed {}", metadata)),
+                    Err(e) => self.fail_with_error(&pb, &metadata.to_string(), e.to_string()),
+                }
+                streamed
+            })
+            .buffered(options.parallel)
+            .try_collect()
+            .await?;
+
+        let mut samples = Vec::new();
+        let mut sources = Vec::new();
+        let mut cue_tracks = Vec::new();
+        let mut chapters = Vec::new();
+        let mut duration_ms = 0;
+        for ((mut streamed, source), metadata) in streamed.into_iter().zip(metadata.iter()) {
+            let start = samples.len() as u64;
+            samples.append(&mut streamed.samples);
+            cue_tracks.push(CueTrack {
+                title: metadata.track_name.clone(),
+                performer: artist_names(&metadata.artists),
+                start,
+            });
+            chapters.push(Chapter {
+                title: metadata.track_name.clone(),
+                start,
+                end: samples.len() as u64,
+            });
+            sources.push(source);
+            duration_ms += metadata.duration.max(0) as u64;
+        }
+        let Some(first) = metadata.first() else {
+            return Err(anyhow::anyhow!(
+                "None of the tracks of the album are available"
+            ));
+        };
+
+        let mut tags = first.tags().await?;
+        tags.title = album.name.clone();
+        tags.artists = album.artists.iter().map(|a| a.name.clone()).collect();
+        tags.extra
+            .push((EXPECTED_DURATION_TAG.to_string(), duration_ms.to_string()));
+        tags.extra.extend(album.spotify_ids().to_tags());
+        tags.chapters = chapters;
+        let samples = Samples {
+            samples,
+            ..Default::default()
+        };
+        self.write_files(samples, tags, &album.name, parts, pb)
+            .await?;
+
+        let cues = files
+            .iter()
+            .map(|file| CueSheet {
+                performer: artist_names(&album.artists),
+                title: album.name.clone(),
+                file: file
+                    .path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                format: file.format,
+                tracks: cue_tracks.clone(),
+            })
+            .collect();
+        Ok((sources, cues))
+    }
+
+    /// Moves the complete files in place and writes their CUE sheets next to them.
+    async fn finish_album(
+        parts: &[OutputFile],
+        files: &[OutputFile],
+        cues: &[CueSheet],
+        cue_paths: &[PathBuf],
+    ) -> Result<()> {
+        Self::finish_files(parts, files).await?;
+        for (cue, path) in cues.iter().zip(cue_paths) {
+            tokio::fs::write(path, cue.render()).await?;
+        }
+        Ok(())
+    }
+
+    fn add_album_progress_bar(&self, album: &AlbumMetadata) -> ProgressBar {
+        let pb = self.progress_bar.add(ProgressBar::new_spinner());
+        pb.enable_steady_tick(std::time::Duration::from_millis(100));
+        // Infallible
+        pb.set_style(
+            ProgressStyle::with_template("{spinner:.green} {msg} [{elapsed_precise}]").unwrap(),
+        );
+        pb.set_message(format!("Streaming {}", album.name));
+        pb
+    }
+}
+
+fn artist_names(artists: &[ArtistMetadata]) -> String {
+    artists
+        .iter()
+        .map(|artist| artist.name.clone())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use futures::StreamExt;
+use indicatif::HumanBytes;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
+
+use crate::space::RECHECK_INTERVAL;
+use crate::space::Reservation;
+use crate::space::SpaceCheck;
+use crate::space::estimated_size;
+use crate::space::missing_space;
+use crate::track::Track;
+use crate::track::TrackGroup;
+use crate::track::TrackMetadata;
+
+use super::DownloadOptions;
+use super::Downloader;
+use super::OutputFile;
+
+/// The estimated bytes the files need, by the directory they go to.
+fn needed_space(files: &[OutputFile], duration_ms: u64) -> HashMap<PathBuf, u64> {
+    let mut needed = HashMap::new();
+    add_needed_space(&mut needed, files, duration_ms);
+    needed
+}
+
+fn add_needed_space(needed: &mut HashMap<PathBuf, u64>, files: &[OutputFile], duration_ms: u64) {
+    for file in files {
+        let directory = file.path.parent().unwrap_or(&file.path).to_path_buf();
+        *needed.entry(directory).or_default() += estimated_size(file.format, duration_ms);
+    }
+}
+
+impl Downloader {
+    /// Estimates the size of the files the download is going to write, and warns or, with
+    /// `--space-check abort`, gives up when a destination doesn't have room for them. This
+    /// needs the metadata of every track, which is kept for the download itself so it isn't
+    /// fetched twice.
+    pub(super) async fn check_space(
+        &self,
+        groups: &[TrackGroup],
+        options: &DownloadOptions,
+    ) -> Result<()> {
+        if options.space_check == SpaceCheck::Off {
+            return Ok(());
+        }
+
+        let tracks: Vec<&Track> = {
+            let cached = self.metadata.lock().unwrap();
+            groups
+                .iter()
+                .flat_map(|group| &group.tracks)
+                .filter(|track| !cached.contains_key(&track.id))
+                .collect()
+        };
+        let pb = self.progress_bar.add(ProgressBar::new(tracks.len() as u64));
+        pb.enable_steady_tick(std::time::Duration::from_millis(100));
+        // Infallible
+        pb.set_style(
+            ProgressStyle::with_template("{spinner:.green} {msg} [{elapsed_precise}] {pos}/{len}")
+                .unwrap(),
+        );
+        pb.set_message("Estimating the size of the download");
+        let fetched: Vec<(&Track, Result<TrackMetadata>)> = futures::stream::iter(tracks)
+            .take_while(|_| futures::future::ready(!self.shutdown.is_stopped()))
+            .map(|track| async move {
+                let metadata = track.metadata(&self.session, &options.retry).await;
+                (track, metadata)
+            })
+            .buffered(options.parallel)
+            .inspect(|_| pb.inc(1))
+            .collect()
+            .await;
+        pb.finish_and_clear();
+        let mut cache = self.metadata.lock().unwrap();
+        for (track, metadata) in fetched {
+            match metadata {
+                Ok(metadata) => {
+                    cache.insert(track.id, metadata);
+                }
+                // Left to the download to report
+                Err(e) => {
+                    tracing::debug!("No metadata for {:?} to estimate its size: {}", track.id, e)
+                }
+            }
+        }
+
+        let mut needed = HashMap::new();
+        for group in groups {
+            let metadata: Vec<&TrackMetadata> = group
+                .tracks
+                .iter()
+                .filter_map(|track| cache.get(&track.id))
+                .collect();
+            if options.single_file && group.album {
+                let Some(first) = metadata.first() else {
+                    continue;
+                };
+                let duration = metadata
+                    .iter()
+                    .map(|metadata| metadata.duration.max(0) as u64)
+                    .sum();
+                let files = options.pending(options.album_paths(&first.album));
+                add_needed_space(&mut needed, &files, duration);
+            } else {
+                for metadata in metadata {
+                    let files = options.pending(options.output_paths(metadata));
+                    add_needed_space(&mut needed, &files, metadata.duration.max(0) as u64);
+                }
+            }
+        }
+        drop(cache);
+        tracing::info!(
+            "The download needs about {}",
+            HumanBytes(needed.values().sum())
+        );
+
+        let mut missing = missing_space(&needed, 0).into_iter();
+        if options.space_check == SpaceCheck::Abort
+            && let Some(missing) = missing.next()
+        {
+            return Err(missing.into());
+        }
+        for missing in missing {
+            tracing::warn!("{}", missing);
+            let _ = self.progress_bar.println(
+                console::style(format!(
+                    "Warning! {}. Tracks will wait for space before they start",
+                    missing
+                ))
+                .yellow()
+                .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// The metadata of the track, fetched before the download started if it was, or given by
+    /// [`Downloader::with_metadata`].
+    pub(super) async fn track_metadata(
+        &self,
+        track: &Track,
+        options: &DownloadOptions,
+    ) -> Result<TrackMetadata> {
+        let cached = self.metadata.lock().unwrap().remove(&track.id);
+        match cached {
+            Some(metadata) => Ok(metadata),
+            None => track.metadata(&self.session, &options.retry).await,
+        }
+    }
+
+    /// Waits until the destinations have room for `files` on top of the ones in progress,
+    /// pausing the download. Gives up on waiting once the download is stopped, and doesn't
+    /// wait at all when `--space-check` is off.
+    pub(super) async fn wait_for_space(
+        &self,
+        name: &str,
+        files: &[OutputFile],
+        duration_ms: u64,
+        options: &DownloadOptions,
+    ) -> Option<Reservation<'_>> {
+        if options.space_check == SpaceCheck::Off {
+            return None;
+        }
+        let needed = needed_space(files, duration_ms);
+        let mut paused = false;
+        loop {
+            match self.space.try_reserve(&needed) {
+                Ok(reservation) => {
+                    if paused {
+                        tracing::info!("Resuming with {}", name);
+                    }
+                    return Some(reservation);
+                }
+                Err(e) if !paused => {
+                    tracing::warn!("Pausing before {}: {}", name, e);
+                    let _ = self.progress_bar.println(
+                        console::style(format!(
+                            "Paused! {}. Waiting for space before downloading {}",
+                            e, name
+                        ))
+                        .yellow()
+                        .to_string(),
+                    );
+                    paused = true;
+                }
+                Err(_) => {}
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(RECHECK_INTERVAL) => {}
+                _ = self.shutdown.stopped() => return None,
+            }
+        }
+    }
+}
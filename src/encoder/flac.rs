@@ -1,17 +1,27 @@
 use flacenc::bitsink::ByteSink;
 use flacenc::component::BitRepr;
 use flacenc::error::Verify;
+use tokio_util::sync::CancellationToken;
 
 use super::EncodedStream;
 use super::Encoder;
 use super::Samples;
+use crate::shutdown::Cancelled;
 
 #[derive(Debug)]
 pub struct FlacEncoder;
 
 #[async_trait::async_trait]
 impl Encoder for FlacEncoder {
-    async fn encode(&self, samples: Samples) -> anyhow::Result<EncodedStream> {
+    async fn encode(
+        &self,
+        samples: Samples,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<EncodedStream> {
+        if cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+
         let source = flacenc::source::MemSource::from_samples(
             &samples.to_s24(),
             samples.channels as usize,
@@ -38,6 +48,11 @@ impl Encoder for FlacEncoder {
             })
             .await??;
 
+        // The encoder can't be interrupted, but there's no point in returning the result
+        if cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+
         Ok(EncodedStream::new(byte_sink))
     }
 }
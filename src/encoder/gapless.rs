@@ -0,0 +1,265 @@
+//! Gapless playback information for MP3 files.
+//!
+//! MP3 frames always hold 1152 samples, so the encoder adds silence before the audio (the encoder
+//! delay) and after it (the padding). Players only know how much to trim if the file tells them,
+//! either in the LAME tag at the start of the stream or in the iTunSMPB comment Apple uses.
+
+use anyhow::Result;
+
+/// Samples in an MPEG-1 Layer III frame, per channel.
+const SAMPLES_PER_FRAME: u64 = 1152;
+/// The delay LAME adds at the start of the stream, in samples per channel.
+const ENCODER_DELAY: u64 = 576;
+/// The delay added by the decoder on top of the encoder's, which iTunSMPB includes.
+const DECODER_DELAY: u64 = 528 + 1;
+
+/// Kbps by bitrate index, for MPEG-1 Layer III.
+const BITRATES: [u32; 16] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 0,
+];
+/// Hz by sample rate index, for MPEG-1.
+const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+/// How much silence surrounds the audio of an MP3 stream.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct GaplessInfo {
+    /// Samples per channel before the audio.
+    pub delay: u64,
+    /// Samples per channel after the audio.
+    pub padding: u64,
+    /// Samples per channel of actual audio.
+    pub samples: u64,
+}
+
+impl GaplessInfo {
+    /// The iTunSMPB comment, which counts the decoder delay in the priming samples.
+    pub fn itunsmpb(&self) -> String {
+        let delay = self.delay + DECODER_DELAY;
+        let padding = self.padding.saturating_sub(DECODER_DELAY);
+        let mut value = format!(" 00000000 {:08X} {:08X} {:016X}", delay, padding, self.samples);
+        for _ in 0..8 {
+            value.push_str(" 00000000");
+        }
+        value
+    }
+
+    /// Reads back an iTunSMPB comment written by [`GaplessInfo::itunsmpb`].
+    pub fn from_itunsmpb(value: &str) -> Option<Self> {
+        let fields: Vec<u64> = value
+            .split_whitespace()
+            .skip(1)
+            .take(3)
+            .map(|field| u64::from_str_radix(field, 16).ok())
+            .collect::<Option<_>>()?;
+        let [delay, padding, samples] = fields[..] else {
+            return None;
+        };
+        Some(GaplessInfo {
+            delay: delay.checked_sub(DECODER_DELAY)?,
+            padding: padding + DECODER_DELAY,
+            samples,
+        })
+    }
+}
+
+/// The fields of an MPEG-1 Layer III frame header we care about.
+struct FrameHeader {
+    bytes: [u8; 4],
+    bitrate: u32,
+    sample_rate: u32,
+    padding: bool,
+}
+
+impl FrameHeader {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let bytes: [u8; 4] = bytes.get(..4)?.try_into().ok()?;
+        let sync = bytes[0] == 0xFF && bytes[1] & 0xE0 == 0xE0;
+        let mpeg1 = (bytes[1] >> 3) & 0b11 == 0b11;
+        let layer3 = (bytes[1] >> 1) & 0b11 == 0b01;
+        if !sync || !mpeg1 || !layer3 {
+            return None;
+        }
+        let bitrate = BITRATES[(bytes[2] >> 4) as usize];
+        let sample_rate = *SAMPLE_RATES.get(((bytes[2] >> 2) & 0b11) as usize)?;
+        if bitrate == 0 {
+            return None;
+        }
+        Some(FrameHeader {
+            bytes,
+            bitrate,
+            sample_rate,
+            padding: bytes[2] & 0b10 != 0,
+        })
+    }
+
+    fn frame_length(&self) -> usize {
+        (144 * self.bitrate * 1000 / self.sample_rate) as usize + self.padding as usize
+    }
+
+    fn side_info_length(&self) -> usize {
+        let mono = self.bytes[3] >> 6 == 0b11;
+        if mono { 17 } else { 32 }
+    }
+}
+
+/// Fills the blank frame LAME leaves at the start of the stream with an Info frame and a LAME
+/// tag, so players can trim the silence around `samples` samples per channel of audio.
+pub fn write_lame_tag(stream: &mut [u8], samples: u64) -> Result<GaplessInfo> {
+    let header = FrameHeader::parse(stream)
+        .ok_or(anyhow::anyhow!("The encoder output doesn't start with an MP3 frame"))?;
+    let frame_length = header.frame_length();
+    let blank = stream
+        .get(4..frame_length)
+        .is_some_and(|payload| payload.iter().all(|&byte| byte == 0));
+    if !blank {
+        return Err(anyhow::anyhow!("The encoder didn't leave room for a LAME tag"));
+    }
+
+    let audio = &stream[frame_length..];
+    let mut frames = 0u64;
+    let mut position = 0;
+    while let Some(frame) = audio.get(position..).and_then(FrameHeader::parse) {
+        frames += 1;
+        position += frame.frame_length();
+    }
+
+    let info = GaplessInfo {
+        delay: ENCODER_DELAY,
+        padding: (frames * SAMPLES_PER_FRAME).saturating_sub(ENCODER_DELAY + samples),
+        samples,
+    };
+
+    let mut tag = Vec::with_capacity(frame_length);
+    tag.extend_from_slice(&header.bytes);
+    tag.resize(4 + header.side_info_length(), 0);
+
+    let total_bytes = stream.len() as u32;
+    // Xing header, "Info" instead of "Xing" marks a constant bitrate stream
+    tag.extend_from_slice(b"Info");
+    tag.extend_from_slice(&0x0Fu32.to_be_bytes());
+    tag.extend_from_slice(&(frames as u32).to_be_bytes());
+    tag.extend_from_slice(&total_bytes.to_be_bytes());
+    tag.extend((0..100).map(|i| (i * 256 / 100) as u8));
+    tag.extend_from_slice(&0u32.to_be_bytes());
+
+    // LAME extension
+    tag.extend_from_slice(b"LAME3.100");
+    // Tag revision 0, constant bitrate
+    tag.push(0x01);
+    // Lowpass, replay gain and encoding flags, unknown
+    tag.extend_from_slice(&[0; 10]);
+    tag.push(header.bitrate.min(255) as u8);
+    let delay = info.delay.min(0xFFF) as u32;
+    let padding = info.padding.min(0xFFF) as u32;
+    tag.extend_from_slice(&((delay << 12) | padding).to_be_bytes()[1..]);
+    // Misc, MP3 gain, preset and surround
+    tag.extend_from_slice(&[0; 4]);
+    tag.extend_from_slice(&total_bytes.to_be_bytes());
+    tag.extend_from_slice(&crc16(audio).to_be_bytes());
+    let tag_crc = crc16(&tag);
+    tag.extend_from_slice(&tag_crc.to_be_bytes());
+
+    if tag.len() > frame_length {
+        return Err(anyhow::anyhow!("The LAME tag doesn't fit in the first frame"));
+    }
+    stream[..tag.len()].copy_from_slice(&tag);
+    Ok(info)
+}
+
+/// CRC-16 as used by the LAME tag, polynomial 0x8005 reflected and no initial value.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &byte| {
+        let mut crc = crc ^ byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MPEG-1 Layer III, 320 kbps, 44.1 kHz, stereo, without padding: 1044 bytes per frame.
+    const HEADER: [u8; 4] = [0xFF, 0xFB, 0xE0, 0x00];
+    const FRAME_LENGTH: usize = 1044;
+
+    /// A blank first frame followed by `frames` frames of audio.
+    fn stream(frames: usize) -> Vec<u8> {
+        let mut stream = Vec::new();
+        for frame in 0..=frames {
+            stream.extend_from_slice(&HEADER);
+            let payload = if frame == 0 { 0x00 } else { 0x55 };
+            stream.resize(stream.len() + FRAME_LENGTH - HEADER.len(), payload);
+        }
+        stream
+    }
+
+    #[test]
+    fn writes_the_lame_tag() {
+        let mut stream = stream(10);
+        let audio = stream[FRAME_LENGTH..].to_vec();
+
+        let info = write_lame_tag(&mut stream, 10000).unwrap();
+
+        assert_eq!(
+            info,
+            GaplessInfo {
+                delay: 576,
+                padding: 10 * 1152 - 576 - 10000,
+                samples: 10000,
+            }
+        );
+        let be32 = |at: usize| u32::from_be_bytes(stream[at..at + 4].try_into().unwrap());
+        assert_eq!(stream[..4], HEADER);
+        // The Xing header goes after the side information
+        assert!(stream[4..36].iter().all(|&byte| byte == 0));
+        assert_eq!(&stream[36..40], b"Info");
+        assert_eq!(be32(40), 0x0F);
+        assert_eq!(be32(44), 10);
+        assert_eq!(be32(48), stream.len() as u32);
+        assert_eq!(&stream[156..165], b"LAME3.100");
+        assert_eq!(stream[176], 255);
+        // 12 bits of delay followed by 12 bits of padding
+        assert_eq!(stream[177..180], [0x24, 0x03, 0xB0]);
+        assert_eq!(be32(184), stream.len() as u32);
+        assert_eq!(stream[188..190], crc16(&audio).to_be_bytes());
+        assert_eq!(stream[190..192], crc16(&stream[..190]).to_be_bytes());
+        // The rest of the first frame stays blank and the audio is left alone
+        assert!(stream[192..FRAME_LENGTH].iter().all(|&byte| byte == 0));
+        assert_eq!(stream[FRAME_LENGTH..], audio[..]);
+    }
+
+    #[test]
+    fn needs_a_blank_first_frame() {
+        let mut stream = stream(2);
+        stream[100] = 1;
+        assert!(write_lame_tag(&mut stream, 1000).is_err());
+        assert!(write_lame_tag(&mut [0; 16], 1000).is_err());
+    }
+
+    #[test]
+    fn itunsmpb_round_trip() {
+        let info = GaplessInfo {
+            delay: 576,
+            padding: 1500,
+            samples: 9_876_543,
+        };
+        let value = info.itunsmpb();
+
+        assert!(
+            value.starts_with(" 00000000 00000451 000003CB 000000000096B43F"),
+            "{}",
+            value
+        );
+        assert_eq!(value.split_whitespace().count(), 12);
+        assert_eq!(GaplessInfo::from_itunsmpb(&value), Some(info));
+        assert_eq!(GaplessInfo::from_itunsmpb(" 00000000 00000010"), None);
+        assert_eq!(GaplessInfo::from_itunsmpb("not a comment"), None);
+    }
+}
@@ -1,4 +1,5 @@
 mod flac;
+pub mod gapless;
 #[cfg(feature = "mp3")]
 mod mp3;
 pub mod tags;
@@ -6,8 +7,9 @@ pub mod tags;
 use std::{path::Path, str::FromStr};
 
 use anyhow::Result;
+use tokio_util::sync::CancellationToken;
 
-use self::{flac::FlacEncoder, mp3::Mp3Encoder};
+use self::{flac::FlacEncoder, gapless::GaplessInfo, mp3::Mp3Encoder};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum Format {
@@ -29,6 +31,26 @@ impl FromStr for Format {
     }
 }
 
+/// Several formats, parsed from a comma separated list like `flac,mp3`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Formats(pub Vec<Format>);
+
+impl FromStr for Formats {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut formats = Vec::new();
+        for format in s.split(',').map(str::trim) {
+            let format: Format = format.parse()?;
+            if formats.contains(&format) {
+                return Err(anyhow::anyhow!("{} is listed twice", format.extension()));
+            }
+            formats.push(format);
+        }
+        Ok(Formats(formats))
+    }
+}
+
 impl Format {
     pub fn extension(&self) -> &'static str {
         match self {
@@ -37,6 +59,14 @@ impl Format {
             Format::Mp3 => "mp3",
         }
     }
+
+    /// The format of a file, going by its extension.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        path.as_ref()
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| extension.to_ascii_lowercase().parse().ok())
+    }
 }
 
 const FLAC_ENCODER: &FlacEncoder = &FlacEncoder;
@@ -53,9 +83,12 @@ pub fn get_encoder(format: Format) -> &'static dyn Encoder {
 
 #[async_trait::async_trait]
 pub trait Encoder {
-    async fn encode(&self, samples: Samples) -> Result<EncodedStream>;
+    /// Encodes the samples, giving up with [`Cancelled`](crate::shutdown::Cancelled) once
+    /// `cancel` is cancelled.
+    async fn encode(&self, samples: Samples, cancel: &CancellationToken) -> Result<EncodedStream>;
 }
 
+#[derive(Clone)]
 pub struct Samples {
     pub samples: Vec<i32>,
     pub sample_rate: u32,
@@ -76,7 +109,7 @@ impl Samples {
     pub fn to_s24(&self) -> Vec<i32> {
         self.samples
             .iter()
-            .map(|&sample| (sample >> 8) as i32) // Convert to S24 by shifting down
+            .map(|&sample| sample >> 8) // Convert to S24 by shifting down
             .collect()
     }
 
@@ -95,23 +128,41 @@ impl Default for Samples {
 
 pub struct EncodedStream {
     pub stream: Vec<u8>,
+    /// The silence the encoder added around the audio, for the formats that need it.
+    pub gapless: Option<GaplessInfo>,
 }
 
 impl EncodedStream {
     pub fn new(stream: Vec<u8>) -> Self {
-        EncodedStream { stream }
+        EncodedStream {
+            stream,
+            gapless: None,
+        }
+    }
+
+    pub fn with_gapless(self, gapless: GaplessInfo) -> Self {
+        EncodedStream {
+            gapless: Some(gapless),
+            ..self
+        }
     }
 
     pub async fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        if !path.as_ref().exists() {
+        let path = path.as_ref();
+        if !path.exists() {
             tokio::fs::create_dir_all(
-                path.as_ref()
-                    .parent()
+                path.parent()
                     .ok_or(anyhow::anyhow!("Could not create path"))?,
             )
             .await?;
         }
-        tokio::fs::write(path, &self.stream).await?;
+        tokio::fs::write(path, &self.stream).await.map_err(|e| {
+            if e.kind() == std::io::ErrorKind::StorageFull {
+                anyhow::anyhow!("The disk is full, could not write {}", path.display())
+            } else {
+                anyhow::anyhow!("Could not write {}: {}", path.display(), e)
+            }
+        })?;
         Ok(())
     }
 }
@@ -1,12 +1,15 @@
 use anyhow::Ok;
 use anyhow::anyhow;
 use mp3lame_encoder::Builder;
-use mp3lame_encoder::FlushNoGap;
+use mp3lame_encoder::FlushGap;
 use mp3lame_encoder::InterleavedPcm;
+use tokio_util::sync::CancellationToken;
 
 use super::EncodedStream;
 use super::Encoder;
 use super::Samples;
+use super::gapless;
+use crate::shutdown::Cancelled;
 
 pub struct Mp3Encoder;
 
@@ -23,6 +26,10 @@ impl Mp3Encoder {
         builder
             .set_brate(mp3lame_encoder::Bitrate::Kbps320)
             .map_err(|e| anyhow::anyhow!("Failed to set bitrate for mp3 encoder: {}", e))?;
+        // Leaves a blank frame at the start of the stream, which gets the LAME tag
+        builder
+            .set_to_write_vbr_tag(true)
+            .map_err(|e| anyhow::anyhow!("Failed to enable the LAME tag: {}", e))?;
 
         builder
             .build()
@@ -32,31 +39,46 @@ impl Mp3Encoder {
 
 #[async_trait::async_trait]
 impl Encoder for Mp3Encoder {
-    async fn encode(&self, samples: Samples) -> anyhow::Result<EncodedStream> {
+    async fn encode(
+        &self,
+        samples: Samples,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<EncodedStream> {
         let mut mp3_encoder = Self::build_encoder(samples.sample_rate, samples.channels)?;
+        let cancel = cancel.clone();
+        let samples_per_channel = samples.samples.len() as u64 / samples.channels as u64;
 
-        let mp3_out_buffer = tokio::task::spawn_blocking(move || {
-            let input = InterleavedPcm(samples.samples.as_slice());
+        let (mp3_out_buffer, gapless) = tokio::task::spawn_blocking(move || {
             let mut mp3_out_buffer = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(
                 samples.samples.len(),
             ));
-            let encoded_size = mp3_encoder
-                .encode(input, mp3_out_buffer.spare_capacity_mut())
-                .map_err(|e| anyhow!("Failed to encode mp3: {}", e))?;
-            unsafe {
-                mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+            // A second of audio at a time, so cancelling doesn't wait for the whole track
+            let chunk_size = (samples.sample_rate * samples.channels) as usize;
+            for chunk in samples.samples.chunks(chunk_size) {
+                if cancel.is_cancelled() {
+                    return Err(Cancelled.into());
+                }
+                mp3_out_buffer.reserve(mp3lame_encoder::max_required_buffer_size(chunk.len()));
+                let encoded_size = mp3_encoder
+                    .encode(InterleavedPcm(chunk), mp3_out_buffer.spare_capacity_mut())
+                    .map_err(|e| anyhow!("Failed to encode mp3: {}", e))?;
+                unsafe {
+                    mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+                }
             }
 
             let encoded_size = mp3_encoder
-                .flush::<FlushNoGap>(mp3_out_buffer.spare_capacity_mut())
+                .flush::<FlushGap>(mp3_out_buffer.spare_capacity_mut())
                 .map_err(|e| anyhow!("Failed to flush mp3 encoder: {}", e))?;
             unsafe {
                 mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
             }
-            Ok(mp3_out_buffer)
+
+            let gapless = gapless::write_lame_tag(&mut mp3_out_buffer, samples_per_channel)?;
+            Ok((mp3_out_buffer, gapless))
         })
         .await??;
 
-        Ok(EncodedStream::new(mp3_out_buffer))
+        Ok(EncodedStream::new(mp3_out_buffer).with_gapless(gapless))
     }
 }
@@ -1,30 +1,143 @@
+use std::path::Path;
+
 use anyhow::Result;
+use audiotags::AudioTag;
+use audiotags::FlacTag;
+use audiotags::Id3v2Tag;
 use audiotags::Picture;
-use audiotags::Tag;
-use audiotags::TagType;
 use bytes::Bytes;
+use id3::TagLike;
+use librespot::playback::NUM_CHANNELS;
+use serde::Serialize;
 
 use crate::encoder::Format;
+use crate::encoder::gapless::GaplessInfo;
+use crate::stream::progress::duration_for_samples;
 
+#[derive(Clone)]
 pub struct Tags {
     pub title: String,
     pub artists: Vec<String>,
     pub album_title: String,
     pub album_cover: Option<Bytes>,
+    /// Free-form fields, stored as Vorbis comments in FLAC and TXXX frames in MP3.
+    pub extra: Vec<(String, String)>,
+    /// Stored as an iTunSMPB comment in MP3, FLAC doesn't need it.
+    pub gapless: Option<GaplessInfo>,
+    /// Stored as CHAP and CTOC frames in MP3 and as a CUESHEET block in FLAC.
+    pub chapters: Vec<Chapter>,
 }
 
-pub async fn store_tags(path: String, tags: &Tags, format: Format) -> Result<()> {
-    let tag_type = match format {
-        Format::Mp3 => TagType::Id3v2,
-        Format::Flac => TagType::Flac,
-    };
+/// A part of a file holding several tracks, positions are in interleaved samples.
+#[derive(Debug, Clone)]
+pub struct Chapter {
+    pub title: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// The tags linking a file back to Spotify, see [`SpotifyIds`].
+pub const TRACK_URI_TAG: &str = "SPOTIFY_TRACK_URI";
+pub const TRACK_ID_TAG: &str = "SPOTIFY_TRACK_ID";
+pub const ALBUM_ID_TAG: &str = "SPOTIFY_ALBUM_ID";
+/// Every artist of the track, separated by [`ID_SEPARATOR`].
+pub const ARTIST_ID_TAG: &str = "SPOTIFY_ARTIST_ID";
+pub const ISRC_TAG: &str = "ISRC";
+pub const SOURCE_URL_TAG: &str = "SOURCE_URL";
+
+const ID_SEPARATOR: char = ';';
+
+/// The identifiers linking a file back to Spotify, stored as free-form fields so other tools
+/// can match and dedupe files. Single-file albums only have the album and artist ones.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize)]
+pub struct SpotifyIds {
+    pub track_uri: Option<String>,
+    /// The base62 id of the track.
+    pub track_id: Option<String>,
+    pub album_id: Option<String>,
+    pub artist_ids: Vec<String>,
+    pub isrc: Option<String>,
+    /// The `open.spotify.com` URL the file was downloaded from.
+    pub source_url: Option<String>,
+}
+
+impl SpotifyIds {
+    /// The fields to add to [`Tags::extra`].
+    pub fn to_tags(&self) -> Vec<(String, String)> {
+        let artist_ids = Some(self.artist_ids.join(&ID_SEPARATOR.to_string()))
+            .filter(|ids| !ids.is_empty());
+        [
+            (TRACK_URI_TAG, self.track_uri.clone()),
+            (TRACK_ID_TAG, self.track_id.clone()),
+            (ALBUM_ID_TAG, self.album_id.clone()),
+            (ARTIST_ID_TAG, artist_ids),
+            (ISRC_TAG, self.isrc.clone()),
+            (SOURCE_URL_TAG, self.source_url.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value?)))
+        .collect()
+    }
+}
+
+/// Reads back the identifiers stored in a file, whatever its format.
+pub fn read_spotify_ids(path: &Path) -> Result<SpotifyIds> {
+    let format = Format::from_path(path)
+        .ok_or_else(|| anyhow::anyhow!("{} is not a FLAC or MP3 file", path.display()))?;
+    let read = |key| read_extra_tag(path, key, format);
+    Ok(SpotifyIds {
+        track_uri: read(TRACK_URI_TAG)?,
+        track_id: read(TRACK_ID_TAG)?,
+        album_id: read(ALBUM_ID_TAG)?,
+        artist_ids: read(ARTIST_ID_TAG)?
+            .map(|ids| ids.split(ID_SEPARATOR).map(str::to_string).collect())
+            .unwrap_or_default(),
+        isrc: read(ISRC_TAG)?,
+        source_url: read(SOURCE_URL_TAG)?,
+    })
+}
 
+/// Tags a file that was just encoded.
+pub async fn store_tags(path: String, tags: &Tags, format: Format) -> Result<()> {
     if format == Format::Mp3 {
+        // The encoder doesn't write an ID3 tag, and the other writers expect one
         let tag = id3::Tag::new();
         tag.write_to_path(&path, id3::Version::Id3v24)?;
     }
+    update_all_tags(path, tags, format).await
+}
+
+/// Writes every tag of `tags` to a file that is already tagged, replacing the ones it has of
+/// the same kind and leaving the others, e.g. the ones added by another tagger, as they are.
+pub async fn update_all_tags(path: String, tags: &Tags, format: Format) -> Result<()> {
+    store_main_tags(&path, tags, format)?;
 
-    let mut tag = Tag::new().with_tag_type(tag_type).read_from_path(&path)?;
+    if !tags.extra.is_empty() {
+        store_extra_tags(&path, &tags.extra, format)?;
+    }
+    if let Some(gapless) = &tags.gapless
+        && format == Format::Mp3
+    {
+        store_gapless_info(&path, gapless)?;
+    }
+    if !tags.chapters.is_empty() {
+        store_chapters(&path, &tags.chapters, format)?;
+    }
+    Ok(())
+}
+
+/// Updates the title, artists, album and cover of a file, leaving its other tags as they are.
+pub async fn update_tags(path: String, tags: &Tags, format: Format) -> Result<()> {
+    store_main_tags(&path, tags, format)
+}
+
+fn store_main_tags(path: &str, tags: &Tags, format: Format) -> Result<()> {
+    // Read by format rather than through `Tag`, which goes by the extension even when given
+    // the tag type, and files are tagged while they still end in `.part`
+    let mut tag: Box<dyn AudioTag + Send + Sync> = match format {
+        Format::Mp3 => Box::new(Id3v2Tag::read_from_path(path)?),
+        Format::Flac => Box::new(FlacTag::read_from_path(path)?),
+    };
     tag.set_title(&tags.title);
 
     let artists: String = tags.artists
@@ -41,6 +154,205 @@ pub async fn store_tags(path: String, tags: &Tags, format: Format) -> Result<()>
         ));
     }
 
-    tag.write_to_path(&path)?;
+    tag.write_to_path(path)?;
     Ok(())
 }
+
+fn store_extra_tags(path: &str, extra: &[(String, String)], format: Format) -> Result<()> {
+    match format {
+        Format::Mp3 => {
+            let mut tag = id3::Tag::read_from_path(path)?;
+            for (key, value) in extra {
+                tag.add_frame(id3::frame::ExtendedText {
+                    description: key.clone(),
+                    value: value.clone(),
+                });
+            }
+            tag.write_to_path(path, id3::Version::Id3v24)?;
+        }
+        Format::Flac => {
+            let mut tag = metaflac::Tag::read_from_path(path)?;
+            for (key, value) in extra {
+                tag.set_vorbis(key.as_str(), vec![value.as_str()]);
+            }
+            tag.save()?;
+        }
+    }
+    Ok(())
+}
+
+fn store_gapless_info(path: &str, gapless: &GaplessInfo) -> Result<()> {
+    let mut tag = id3::Tag::read_from_path(path)?;
+    tag.add_frame(id3::frame::Comment {
+        lang: "eng".to_string(),
+        description: "iTunSMPB".to_string(),
+        text: gapless.itunsmpb(),
+    });
+    tag.write_to_path(path, id3::Version::Id3v24)?;
+    Ok(())
+}
+
+fn store_chapters(path: &str, chapters: &[Chapter], format: Format) -> Result<()> {
+    match format {
+        Format::Mp3 => {
+            let mut tag = id3::Tag::read_from_path(path)?;
+            let milliseconds = |samples| duration_for_samples(samples).as_millis() as u32;
+            let mut elements = Vec::new();
+            for (number, chapter) in chapters.iter().enumerate() {
+                let element_id = format!("chp{}", number + 1);
+                tag.add_frame(id3::frame::Chapter {
+                    element_id: element_id.clone(),
+                    start_time: milliseconds(chapter.start),
+                    end_time: milliseconds(chapter.end),
+                    // Unused, the times are what players go by
+                    start_offset: u32::MAX,
+                    end_offset: u32::MAX,
+                    frames: vec![id3::Frame::text("TIT2", chapter.title.clone())],
+                });
+                elements.push(element_id);
+            }
+            tag.add_frame(id3::frame::TableOfContents {
+                element_id: "toc".to_string(),
+                top_level: true,
+                ordered: true,
+                elements,
+                frames: Vec::new(),
+            });
+            tag.write_to_path(path, id3::Version::Id3v24)?;
+        }
+        Format::Flac => {
+            // Track numbers are a byte and 255 is the lead-out, CD style
+            if chapters.len() > 99 {
+                tracing::warn!("Not storing a cue sheet in {}, it has more than 99 tracks", path);
+                return Ok(());
+            }
+            let per_channel = |samples| samples / NUM_CHANNELS as u64;
+            let mut tracks: Vec<metaflac::block::CueSheetTrack> = chapters
+                .iter()
+                .enumerate()
+                .map(|(number, chapter)| metaflac::block::CueSheetTrack {
+                    offset: per_channel(chapter.start),
+                    number: number as u8 + 1,
+                    indices: vec![metaflac::block::CueSheetTrackIndex {
+                        offset: 0,
+                        point_num: 1,
+                    }],
+                    ..Default::default()
+                })
+                .collect();
+            let end = chapters.iter().map(|chapter| chapter.end).max().unwrap_or(0);
+            tracks.push(metaflac::block::CueSheetTrack {
+                offset: per_channel(end),
+                number: 255,
+                ..Default::default()
+            });
+
+            let mut tag = metaflac::Tag::read_from_path(path)?;
+            tag.remove_blocks(metaflac::BlockType::CueSheet);
+            tag.push_block(metaflac::Block::CueSheet(metaflac::block::CueSheet {
+                // Not a CD, so the offsets don't have to fall on CD frames
+                is_cd: false,
+                tracks,
+                ..Default::default()
+            }));
+            tag.save()?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads back the gapless info of an MP3 file written with [`Tags::gapless`].
+pub fn read_gapless_info(path: &Path) -> Result<Option<GaplessInfo>> {
+    let tag = id3::Tag::read_from_path(path)?;
+    let gapless = tag
+        .comments()
+        .find(|comment| comment.description == "iTunSMPB")
+        .and_then(|comment| GaplessInfo::from_itunsmpb(&comment.text));
+    Ok(gapless)
+}
+
+/// Reads back a free-form field written with [`Tags::extra`].
+pub fn read_extra_tag(path: &Path, key: &str, format: Format) -> Result<Option<String>> {
+    let value = match format {
+        Format::Mp3 => id3::Tag::read_from_path(path)?
+            .extended_texts()
+            .find(|text| text.description == key)
+            .map(|text| text.value.clone()),
+        Format::Flac => metaflac::Tag::read_from_path(path)?
+            .get_vorbis(key)
+            .and_then(|mut values| values.next())
+            .map(str::to_string),
+    };
+    Ok(value)
+}
+
+/// Reads back every free-form field written with [`Tags::extra`], in the order of their keys.
+pub fn read_extra_tags(path: &Path, format: Format) -> Result<Vec<(String, String)>> {
+    let mut extra: Vec<(String, String)> = match format {
+        Format::Mp3 => id3::Tag::read_from_path(path)?
+            .extended_texts()
+            .map(|text| (text.description.clone(), text.value.clone()))
+            .collect(),
+        Format::Flac => {
+            let tag = metaflac::Tag::read_from_path(path)?;
+            let Some(comments) = tag.vorbis_comments() else {
+                return Ok(Vec::new());
+            };
+            comments
+                .comments
+                .iter()
+                // The ones audiotags writes aren't free-form
+                .filter(|(key, _)| !matches!(key.as_str(), "TITLE" | "ARTIST" | "ALBUM"))
+                .filter_map(|(key, values)| Some((key.clone(), values.first()?.clone())))
+                .collect()
+        }
+    };
+    extra.sort();
+    Ok(extra)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn updating_keeps_foreign_frames() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("track.mp3");
+        std::fs::write(&path, [0u8; 16]).unwrap();
+        let mut tag = id3::Tag::new();
+        tag.set_title("Old title");
+        tag.add_frame(id3::frame::ExtendedText {
+            description: "MusicBrainz Album Id".to_string(),
+            value: "1234".to_string(),
+        });
+        tag.add_frame(id3::frame::ExtendedText {
+            description: TRACK_URI_TAG.to_string(),
+            value: "spotify:track:old".to_string(),
+        });
+        tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+
+        let tags = Tags {
+            title: "New title".to_string(),
+            artists: vec!["Artist".to_string()],
+            album_title: "Album".to_string(),
+            album_cover: None,
+            extra: vec![(TRACK_URI_TAG.to_string(), "spotify:track:new".to_string())],
+            gapless: None,
+            chapters: Vec::new(),
+        };
+        update_all_tags(path.to_string_lossy().to_string(), &tags, Format::Mp3)
+            .await
+            .unwrap();
+
+        let tag = id3::Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.title(), Some("New title"));
+        assert_eq!(
+            read_extra_tags(&path, Format::Mp3).unwrap(),
+            vec![
+                ("MusicBrainz Album Id".to_string(), "1234".to_string()),
+                (TRACK_URI_TAG.to_string(), "spotify:track:new".to_string()),
+            ]
+        );
+    }
+}
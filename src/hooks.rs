@@ -0,0 +1,226 @@
+//! Commands run after every track and after the whole download, to hand the files over to
+//! other tools.
+//!
+//! Hooks are run through the shell, with the details of the track or the run in `SPOTIFY_DL_*`
+//! environment variables and as JSON on stdin. A hook failing or timing out is recorded in the
+//! report, but never fails the download.
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::encoder::Format;
+use crate::report::DownloadReport;
+use crate::report::TrackReport;
+use crate::report::TrackStatus;
+use crate::track::TrackMetadata;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    /// Run after every track, whatever happened to it.
+    pub on_track_complete: Option<String>,
+    /// Run once all the tracks are done.
+    pub on_complete: Option<String>,
+    /// How long a hook may run before it is killed.
+    pub timeout: Duration,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks {
+            on_track_complete: None,
+            on_complete: None,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookStatus {
+    Succeeded,
+    Failed,
+    TimedOut,
+}
+
+/// What happened when running a hook.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookOutcome {
+    pub command: String,
+    pub status: HookStatus,
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+}
+
+impl HookOutcome {
+    pub fn is_ok(&self) -> bool {
+        self.status == HookStatus::Succeeded
+    }
+}
+
+/// The input of `--on-track-complete`.
+#[derive(Serialize)]
+struct TrackEvent<'a> {
+    event: &'static str,
+    #[serde(flatten)]
+    report: &'a TrackReport,
+    title: &'a str,
+    artists: Vec<&'a str>,
+    album: &'a str,
+    duration_ms: i32,
+    format: &'static str,
+}
+
+/// The input of `--on-complete`.
+#[derive(Serialize)]
+struct CompleteEvent<'a> {
+    event: &'static str,
+    #[serde(flatten)]
+    report: &'a DownloadReport,
+}
+
+impl Hooks {
+    /// Runs `--on-track-complete` for a track, recording the outcome in its report.
+    pub async fn track_complete(
+        &self,
+        metadata: &TrackMetadata,
+        report: TrackReport,
+        format: Format,
+    ) -> TrackReport {
+        let Some(command) = &self.on_track_complete else {
+            return report;
+        };
+
+        let event = TrackEvent {
+            event: "track_complete",
+            report: &report,
+            title: &metadata.track_name,
+            artists: metadata.artists.iter().map(|a| a.name.as_str()).collect(),
+            album: &metadata.album.name,
+            duration_ms: metadata.duration,
+            format: format.extension(),
+        };
+        let env = vec![
+            ("SPOTIFY_DL_EVENT", event.event.to_string()),
+            ("SPOTIFY_DL_URI", report.uri.clone()),
+            ("SPOTIFY_DL_NAME", report.name.clone()),
+            ("SPOTIFY_DL_TITLE", metadata.track_name.clone()),
+            ("SPOTIFY_DL_ARTISTS", event.artists.join(", ")),
+            ("SPOTIFY_DL_ALBUM", metadata.album.name.clone()),
+            ("SPOTIFY_DL_PATH", report.path.display().to_string()),
+            ("SPOTIFY_DL_FORMAT", format.extension().to_string()),
+            ("SPOTIFY_DL_STATUS", report.status.as_str().to_string()),
+        ];
+        let input = serde_json::to_vec(&event).unwrap_or_default();
+
+        let outcome = self.run(command, env, input).await;
+        if !outcome.is_ok() {
+            tracing::warn!(
+                "Hook for {} didn't succeed: {:?}",
+                report.name,
+                outcome.error
+            );
+        }
+        report.with_hook(outcome)
+    }
+
+    /// Runs `--on-complete` once the download is over.
+    pub async fn complete(&self, report: &DownloadReport) -> Option<HookOutcome> {
+        let command = self.on_complete.as_ref()?;
+
+        let event = CompleteEvent {
+            event: "complete",
+            report,
+        };
+        let count = |status| report.count(status).to_string();
+        let env = vec![
+            ("SPOTIFY_DL_EVENT", event.event.to_string()),
+            ("SPOTIFY_DL_DOWNLOADED", count(TrackStatus::Downloaded)),
+            ("SPOTIFY_DL_SKIPPED", count(TrackStatus::Skipped)),
+            ("SPOTIFY_DL_UNAVAILABLE", count(TrackStatus::Unavailable)),
+            ("SPOTIFY_DL_FAILED", count(TrackStatus::Failed)),
+            ("SPOTIFY_DL_CANCELLED", count(TrackStatus::Cancelled)),
+        ];
+        let input = serde_json::to_vec(&event).unwrap_or_default();
+
+        let outcome = self.run(command, env, input).await;
+        if !outcome.is_ok() {
+            tracing::warn!("Completion hook didn't succeed: {:?}", outcome.error);
+        }
+        Some(outcome)
+    }
+
+    async fn run(&self, command: &str, env: Vec<(&str, String)>, input: Vec<u8>) -> HookOutcome {
+        let outcome = |status, exit_code, error| HookOutcome {
+            command: command.to_string(),
+            status,
+            exit_code,
+            error,
+        };
+
+        tracing::info!("Running hook: {}", command);
+        let run = async {
+            let mut child = shell(command)
+                .envs(env)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                // Dropped on timeout, which kills the hook
+                .kill_on_drop(true)
+                .spawn()?;
+            if let Some(mut stdin) = child.stdin.take() {
+                // Written from another task so a hook that doesn't read its input can't block
+                // us, the pipe is closed once everything is written
+                tokio::spawn(async move {
+                    let _ = stdin.write_all(&input).await;
+                });
+            }
+            child.wait_with_output().await
+        };
+
+        let output = match tokio::time::timeout(self.timeout, run).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => return outcome(HookStatus::Failed, None, Some(e.to_string())),
+            Err(_) => {
+                return outcome(
+                    HookStatus::TimedOut,
+                    None,
+                    Some(format!("killed after {:?}", self.timeout)),
+                );
+            }
+        };
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        tracing::debug!("Hook output: {}{}", stdout, stderr);
+        if output.status.success() {
+            return outcome(HookStatus::Succeeded, output.status.code(), None);
+        }
+        let error = stderr
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .map_or_else(|| output.status.to_string(), str::to_string);
+        outcome(HookStatus::Failed, output.status.code(), Some(error))
+    }
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
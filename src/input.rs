@@ -0,0 +1,204 @@
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::uri::SpotifyLink;
+use crate::uri::parse_link;
+
+const STDIN: &str = "-";
+
+/// Collects the Spotify URIs passed as arguments, in input files or through stdin.
+///
+/// Plain text inputs contain one URI or URL per line; blank lines and `#` comments are ignored.
+/// Files with a `.csv` extension are read as exported playlists, taking the URIs from the first
+/// column whose header mentions a Spotify URI. Duplicated items are only returned once. Short links
+/// are kept as they are, to be resolved when fetching the tracks.
+pub fn read_inputs(args: Vec<String>, input_files: &[PathBuf]) -> Result<Vec<String>> {
+    let mut inputs = Inputs::default();
+
+    for arg in args {
+        if arg == STDIN {
+            inputs.read_stdin()?;
+        } else {
+            inputs.push(&arg, "arguments", None)?;
+        }
+    }
+
+    for file in input_files {
+        if file.as_os_str() == STDIN {
+            inputs.read_stdin()?;
+            continue;
+        }
+
+        let source = file.display().to_string();
+        let reader = std::fs::File::open(file)
+            .map_err(|e| anyhow::anyhow!("Could not open {}: {}", source, e))?;
+        if is_csv(file) {
+            inputs.read_csv(reader, &source)?;
+        } else {
+            inputs.read_lines(std::io::BufReader::new(reader), &source)?;
+        }
+    }
+
+    Ok(inputs.uris)
+}
+
+fn is_csv(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"))
+}
+
+#[derive(Default)]
+struct Inputs {
+    uris: Vec<String>,
+    seen: HashSet<String>,
+    stdin_read: bool,
+}
+
+impl Inputs {
+    fn push(&mut self, input: &str, source: &str, line: Option<usize>) -> Result<()> {
+        let location = match line {
+            Some(line) => format!("{}:{}", source, line),
+            None => source.to_string(),
+        };
+        let uri = match parse_link(input).map_err(|e| anyhow::anyhow!("{}: {}", location, e))? {
+            SpotifyLink::Id(id) => id.to_uri()?,
+            SpotifyLink::ShortLink(link) => link.to_string(),
+        };
+
+        if !self.seen.insert(uri.clone()) {
+            tracing::debug!("Ignoring duplicated input in {}: {}", location, input);
+            return Ok(());
+        }
+        self.uris.push(uri);
+        Ok(())
+    }
+
+    fn read_stdin(&mut self) -> Result<()> {
+        if self.stdin_read {
+            return Ok(());
+        }
+        self.stdin_read = true;
+        self.read_lines(std::io::stdin().lock(), "stdin")
+    }
+
+    fn read_lines<R: BufRead>(&mut self, reader: R, source: &str) -> Result<()> {
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let input = strip_comment(&line).trim();
+            if input.is_empty() {
+                continue;
+            }
+            self.push(input, source, Some(number + 1))?;
+        }
+        Ok(())
+    }
+
+    fn read_csv<R: Read>(&mut self, reader: R, source: &str) -> Result<()> {
+        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+
+        let headers = reader.headers()?.clone();
+        let column = find_uri_column(&headers).ok_or(anyhow::anyhow!(
+            "Could not find a Spotify URI column in {}",
+            source
+        ))?;
+
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |p| p.line() as usize);
+            match record.get(column).map(str::trim) {
+                Some(input) if !input.is_empty() => self.push(input, source, Some(line))?,
+                _ => continue,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    if line.trim_start().starts_with('#') {
+        return "";
+    }
+    match line.find(" #").or_else(|| line.find("\t#")) {
+        Some(index) => &line[..index],
+        None => line,
+    }
+}
+
+fn find_uri_column(headers: &csv::StringRecord) -> Option<usize> {
+    let find = |needle: &str| {
+        headers
+            .iter()
+            .position(|header| header.to_lowercase().contains(needle))
+    };
+    find("uri").or_else(|| find("url"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACK_URI: &str = "spotify:track:4uLU6hMCjMI75M1A2tKUQC";
+
+    #[test]
+    fn strips_comments() {
+        assert_eq!(strip_comment("# a whole line"), "");
+        assert_eq!(strip_comment("   # indented"), "");
+        assert_eq!(
+            strip_comment("spotify:track:id # a comment"),
+            "spotify:track:id"
+        );
+        assert_eq!(
+            strip_comment("spotify:track:id\t# a comment"),
+            "spotify:track:id"
+        );
+        // Not a comment without a space before it, URLs can have fragments
+        assert_eq!(
+            strip_comment("https://open.spotify.com/track/id#part"),
+            "https://open.spotify.com/track/id#part"
+        );
+    }
+
+    #[test]
+    fn finds_the_uri_column() {
+        let headers = |columns: &[&str]| csv::StringRecord::from(columns.to_vec());
+        assert_eq!(
+            find_uri_column(&headers(&["Track Name", "Track URI", "Artist URI(s)"])),
+            Some(1)
+        );
+        assert_eq!(find_uri_column(&headers(&["Name", "Spotify URL"])), Some(1));
+        // A URI column is preferred over a URL one
+        assert_eq!(find_uri_column(&headers(&["url", "uri"])), Some(1));
+        assert_eq!(find_uri_column(&headers(&["Name", "Artist"])), None);
+    }
+
+    #[test]
+    fn reads_lines_and_csv() {
+        let mut inputs = Inputs::default();
+        let lines = format!(
+            "# My playlist\n\n{}\nhttps://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC # again\n",
+            TRACK_URI
+        );
+        inputs.read_lines(lines.as_bytes(), "list.txt").unwrap();
+        let csv = "Track Name,Track URI\nSong,spotify:album:2noRn2Aes5aoNVsU6iWThc\nEmpty,\n";
+        inputs.read_csv(csv.as_bytes(), "playlist.csv").unwrap();
+
+        assert_eq!(
+            inputs.uris,
+            vec![TRACK_URI, "spotify:album:2noRn2Aes5aoNVsU6iWThc"]
+        );
+    }
+
+    #[test]
+    fn tells_where_an_invalid_input_is() {
+        let mut inputs = Inputs::default();
+        let error = inputs
+            .read_lines(format!("{}\nnot a uri\n", TRACK_URI).as_bytes(), "list.txt")
+            .unwrap_err();
+        assert!(error.to_string().contains("list.txt:2"), "{}", error);
+    }
+}
@@ -1,7 +1,24 @@
 pub mod stream;
+pub mod convert;
+pub mod cue;
+pub mod decoder;
 pub mod download;
 pub mod encoder;
+pub mod hooks;
+pub mod input;
+pub mod mirror;
+pub mod plan;
+pub mod report;
+pub mod retag;
+pub mod retry;
+pub mod serve;
 pub mod session;
+pub mod shutdown;
+pub mod space;
+pub mod throttle;
 pub mod track;
+pub mod uri;
+pub mod verify;
+pub mod watch;
 mod utils;
 pub mod log;
\ No newline at end of file
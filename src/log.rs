@@ -7,7 +7,6 @@ use std::sync::Arc;
 use std::sync::Mutex;
 
 use anyhow::Result;
-use once_cell::sync::OnceCell;
 use tracing::level_filters::LevelFilter;
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::EnvFilter;
@@ -20,7 +19,7 @@ use tracing_subscriber::util::SubscriberInitExt;
 
 use crate::utils::get_dot_path;
 
-static LOG_GUARD: OnceCell<WorkerGuard> = OnceCell::new();
+static LOG_GUARD: Mutex<Option<WorkerGuard>> = Mutex::new(None);
 
 const MAX_LOG_SIZE: u64 = 5 * 1024 * 1024; // 5 MB
 
@@ -69,7 +68,7 @@ pub fn configure_logger() -> Result<()> {
 
     let writer = RotatingFileWriter::new(path)?;
     let (non_blocking, guard) = tracing_appender::non_blocking(writer);
-    LOG_GUARD.set(guard).ok();
+    *LOG_GUARD.lock().unwrap() = Some(guard);
 
     let targets = filter::Targets::new()
         .with_target("spotify_dl", tracing::Level::DEBUG)
@@ -94,3 +93,8 @@ pub fn configure_logger() -> Result<()> {
 
     Ok(())
 }
+
+/// Writes out the buffered log lines, the file logger stops working after this.
+pub fn flush_logger() {
+    LOG_GUARD.lock().unwrap().take();
+}
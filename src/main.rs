@@ -1,21 +1,82 @@
-use spotify_dl::download::{DownloadOptions, Downloader};
-use spotify_dl::encoder::Format;
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use spotify_dl::convert::{ConvertOptions, ConvertStatus, convert_library};
+use spotify_dl::download::{DownloadOptions, Downloader, ExtraOutput};
+use spotify_dl::encoder::{Format, Formats};
+use spotify_dl::hooks::Hooks;
+use spotify_dl::input::read_inputs;
 use spotify_dl::log;
+use spotify_dl::mirror::Mirror;
+use spotify_dl::plan::{ListFormat, write_plan};
+use spotify_dl::retag::{RetagOptions, RetagStatus, retag_library, write_changes};
+use spotify_dl::retry::RetryPolicy;
+use spotify_dl::serve::{JobQueue, default_jobs_path};
 use spotify_dl::session::create_session;
-use spotify_dl::track::get_tracks;
+use spotify_dl::shutdown::Shutdown;
+use spotify_dl::space::SpaceCheck;
+use spotify_dl::stream::Quality;
+use spotify_dl::stream::Timeouts;
+use spotify_dl::stream::timeouts::parse_duration_factor;
+use spotify_dl::throttle::{PauseRange, Throttle, parse_rate};
+use spotify_dl::track::{get_track_groups, get_tracks};
+use spotify_dl::verify::{verify_library, write_verification};
+use spotify_dl::watch::{Removal, WatchOptions, Watcher};
 use structopt::StructOpt;
+use structopt::clap::AppSettings;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "spotify-dl",
-    about = "A commandline utility to download music directly from Spotify"
+    about = "A commandline utility to download music directly from Spotify",
+    setting = AppSettings::SubcommandsNegateReqs,
+    setting = AppSettings::ArgsNegateSubcommands
 )]
 struct Opt {
+    #[structopt(flatten)]
+    download: DownloadArgs,
     #[structopt(
-        help = "A list of Spotify URIs or URLs (songs, podcasts, playlists or albums)",
-        required = true
+        long = "dry-run",
+        help = "Print what would be downloaded without downloading anything"
+    )]
+    dry_run: bool,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+struct DownloadArgs {
+    #[structopt(
+        help = "A list of Spotify URIs or URLs (songs, podcasts, playlists or albums). Use - to read them from stdin",
+        required_unless = "input-files"
     )]
     tracks: Vec<String>,
+    #[structopt(
+        short = "i",
+        long = "input-file",
+        name = "input-files",
+        help = "Read Spotify URIs or URLs from a file, one per line, or from an exported playlist CSV. Use - for stdin",
+        parse(from_os_str),
+        number_of_values = 1
+    )]
+    input_files: Vec<PathBuf>,
+    #[structopt(
+        long = "report",
+        help = "Write a JSON report of the download, including the quality of the source of each track",
+        parse(from_os_str)
+    )]
+    report: Option<PathBuf>,
+    #[structopt(flatten)]
+    settings: DownloadSettings,
+}
+
+// How to download, shared by the commands that download or list tracks. Not a doc comment,
+// structopt would use it as the about text of every command flattening it.
+#[derive(Debug, StructOpt)]
+struct DownloadSettings {
     #[structopt(
         short = "d",
         long = "destination",
@@ -32,49 +93,607 @@ struct Opt {
     #[structopt(
         short = "f",
         long = "format",
-        help = "The format to download the tracks in. Default is flac.",
+        help = "The formats to download the tracks in, separated by commas, e.g. flac,mp3. Every track is streamed once whatever the number of formats. Default is flac.",
         default_value = "flac"
     )]
-    format: Format,
+    format: Formats,
+    #[structopt(
+        long = "format-destination",
+        help = "Where to write one of the formats other than the first, e.g. mp3=/music/mp3. Default is the destination",
+        number_of_values = 1
+    )]
+    format_destinations: Vec<ExtraOutput>,
     #[structopt(
         short = "F",
         long = "force",
         help = "Force download even if the file already exists"
     )]
     force: bool,
+    #[structopt(
+        long = "use-alternatives",
+        help = "Download an alternative version of the same recording when a track is unavailable in your region"
+    )]
+    use_alternatives: bool,
+    #[structopt(
+        long = "retries",
+        help = "How many times to retry failed requests to Spotify. Default is 3.",
+        default_value = "3"
+    )]
+    retries: u32,
+    #[structopt(
+        long = "retry-delay",
+        help = "Seconds to wait before the first retry, doubled on every attempt. Default is 10.",
+        default_value = "10"
+    )]
+    retry_delay: u64,
+    #[structopt(
+        long = "retry-max-delay",
+        help = "Maximum seconds to wait between retries. Default is 30.",
+        default_value = "30"
+    )]
+    retry_max_delay: u64,
+    #[structopt(
+        long = "load-timeout",
+        help = "Seconds to wait for a track to start playing, or for each request while opening its file. Default is 30.",
+        default_value = "30"
+    )]
+    load_timeout: u64,
+    #[structopt(
+        long = "stall-timeout",
+        help = "Seconds without receiving any audio before a stream is considered stalled and retried. Default is 60.",
+        default_value = "60"
+    )]
+    stall_timeout: u64,
+    #[structopt(
+        long = "timeout-factor",
+        help = "Give up on a stream that takes longer than this many times the duration of the track. Default is 4.",
+        default_value = "4",
+        parse(try_from_str = parse_duration_factor)
+    )]
+    timeout_factor: f64,
+    #[structopt(
+        long = "length-tolerance",
+        help = "Seconds a track can fall short of its duration before it is considered truncated and downloaded again. Default is 2.",
+        default_value = "2"
+    )]
+    length_tolerance: u64,
+    #[structopt(
+        long = "limit-rate",
+        help = "Limit the bandwidth used to fetch the audio files, in bytes per second, shared by all parallel downloads. Accepts K, M and G suffixes, e.g. 500K. Without --direct, the player fetches the files on its own, so it is held back by the bitrate of the audio instead",
+        parse(try_from_str = parse_rate)
+    )]
+    limit_rate: Option<NonZeroU32>,
+    #[structopt(
+        long = "max-requests",
+        help = "Maximum number of requests to Spotify per minute, shared by all parallel downloads"
+    )]
+    max_requests: Option<NonZeroU32>,
+    #[structopt(
+        long = "pause",
+        help = "Wait a random number of seconds between tracks, e.g. 5 or 2-10"
+    )]
+    pause: Option<PauseRange>,
+    #[structopt(
+        long = "direct",
+        help = "Fetch and decode the audio files directly instead of playing them back. Much faster, but experimental"
+    )]
+    direct: bool,
+    #[structopt(
+        short = "q",
+        long = "quality",
+        help = "The quality of the audio files to download: low (96 kbps), normal (160 kbps) or high (320 kbps). Falls back to the closest available quality",
+        default_value = "high"
+    )]
+    quality: Quality,
+    #[structopt(
+        long = "single-file",
+        help = "Download albums as a single continuous file, with a CUE sheet and chapters marking where each track starts"
+    )]
+    single_file: bool,
+    #[structopt(
+        long = "space-check",
+        help = "What to do when the destination looks too small for the download: warn, abort or off. Unless off, the whole download is estimated first, and tracks wait for space before they start. warn only warns when the download doesn't fit, abort doesn't start it. Default is warn.",
+        default_value = "warn"
+    )]
+    space_check: SpaceCheck,
+    #[structopt(
+        long = "on-track-complete",
+        help = "A command to run after every track, described in SPOTIFY_DL_* environment variables and as JSON on stdin"
+    )]
+    on_track_complete: Option<String>,
+    #[structopt(
+        long = "on-complete",
+        help = "A command to run once all the tracks are done, with the report of the download as JSON on stdin"
+    )]
+    on_complete: Option<String>,
+    #[structopt(
+        long = "hook-timeout",
+        help = "Seconds a hook may run before it is killed. Default is 300.",
+        default_value = "300"
+    )]
+    hook_timeout: u64,
 }
 
-pub fn create_destination_if_required(destination: Option<String>) -> anyhow::Result<()> {
-    if let Some(destination) = destination {
-        if !std::path::Path::new(&destination).exists() {
-            tracing::info!("Creating destination directory: {}", destination);
-            std::fs::create_dir_all(destination)?;
+// Parsed once at startup, boxing the download arguments wouldn't buy anything
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(about = "List the tracks that would be downloaded, without downloading them")]
+    List {
+        #[structopt(flatten)]
+        download: DownloadArgs,
+        #[structopt(
+            short = "o",
+            long = "output",
+            help = "The output format of the list: table, json or csv",
+            default_value = "table"
+        )]
+        output: ListFormat,
+    },
+    #[structopt(
+        about = "Check that the files in a directory downloaded by spotify-dl are complete"
+    )]
+    Verify {
+        #[structopt(
+            help = "The directory to check, including its subdirectories. Default is the current directory",
+            parse(from_os_str)
+        )]
+        directory: Option<PathBuf>,
+        #[structopt(
+            long = "length-tolerance",
+            help = "Seconds a track can fall short of its duration before it is considered truncated. Default is 2.",
+            default_value = "2"
+        )]
+        length_tolerance: u64,
+        #[structopt(
+            short = "o",
+            long = "output",
+            help = "The output format: table, json or csv",
+            default_value = "table"
+        )]
+        output: ListFormat,
+    },
+    #[structopt(
+        about = "Rewrite the tags of the files in a directory downloaded by spotify-dl, without downloading them again"
+    )]
+    Retag {
+        #[structopt(
+            help = "The directory to retag, including its subdirectories. Default is the current directory",
+            parse(from_os_str)
+        )]
+        directory: Option<PathBuf>,
+        #[structopt(
+            long = "dry-run",
+            help = "Show the changes without writing them"
+        )]
+        dry_run: bool,
+        #[structopt(
+            short = "t",
+            long = "parallel",
+            help = "Number of files to look up in parallel. Default is 5.",
+            default_value = "5"
+        )]
+        parallel: usize,
+    },
+    #[structopt(
+        about = "Convert the files in a directory downloaded by spotify-dl to another format, without downloading them again"
+    )]
+    Convert {
+        #[structopt(
+            help = "The directory to convert, including its subdirectories. Default is the current directory",
+            parse(from_os_str)
+        )]
+        directory: Option<PathBuf>,
+        #[structopt(
+            short = "o",
+            long = "output",
+            help = "The directory to write the converted files to, in the same layout",
+            parse(from_os_str)
+        )]
+        output: PathBuf,
+        #[structopt(
+            short = "f",
+            long = "format",
+            help = "The format to convert the files to. Default is mp3.",
+            default_value = "mp3"
+        )]
+        format: Format,
+        #[structopt(
+            short = "t",
+            long = "parallel",
+            help = "Number of files to convert in parallel. Default is 5.",
+            default_value = "5"
+        )]
+        parallel: usize,
+        #[structopt(
+            short = "F",
+            long = "force",
+            help = "Convert the files again even if the output already exists"
+        )]
+        force: bool,
+    },
+    #[structopt(about = "Run as a daemon, downloading the jobs queued through a local HTTP API")]
+    Serve {
+        #[structopt(
+            long = "listen",
+            help = "The address to listen on. Default is 127.0.0.1:7070",
+            default_value = "127.0.0.1:7070"
+        )]
+        listen: SocketAddr,
+        #[structopt(
+            long = "jobs-file",
+            help = "Where to keep the job queue. Default is ~/.spotify-dl/jobs.json",
+            parse(from_os_str)
+        )]
+        jobs_file: Option<PathBuf>,
+        #[structopt(flatten)]
+        settings: DownloadSettings,
+    },
+    #[structopt(
+        about = "Watch playlists and download the tracks added to them, keeping an M3U playlist up to date"
+    )]
+    Watch {
+        #[structopt(help = "The Spotify URIs or URLs of the playlists to watch", required = true)]
+        playlists: Vec<String>,
+        #[structopt(
+            long = "interval",
+            help = "Minutes between two checks of the playlists. Default is 60.",
+            default_value = "60"
+        )]
+        interval: u64,
+        #[structopt(long = "once", help = "Check the playlists once and exit, e.g. from cron")]
+        once: bool,
+        #[structopt(
+            long = "removed",
+            help = "What to do with the files of tracks removed from a playlist: keep, archive or delete. Files still in another watched playlist are always kept",
+            default_value = "keep"
+        )]
+        removed: Removal,
+        #[structopt(flatten)]
+        settings: DownloadSettings,
+    },
+    #[structopt(
+        about = "Make the destination an exact mirror of a playlist, with the files numbered in playlist order"
+    )]
+    Mirror {
+        #[structopt(help = "The Spotify URI or URL of the playlist to mirror")]
+        playlist: String,
+        #[structopt(
+            long = "trash",
+            help = "Where to move the tracks removed from the playlist. Default is .trash in the destination",
+            parse(from_os_str)
+        )]
+        trash: Option<PathBuf>,
+        #[structopt(flatten)]
+        settings: DownloadSettings,
+    },
+}
+
+impl DownloadArgs {
+    fn inputs(&self) -> anyhow::Result<Vec<String>> {
+        let inputs = read_inputs(self.tracks.clone(), &self.input_files)?;
+        if inputs.is_empty() {
+            return Err(anyhow::anyhow!("No tracks provided"));
         }
+        Ok(inputs)
     }
-    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    log::configure_logger()?;
+impl DownloadSettings {
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::new(
+            self.retries,
+            Duration::from_secs(self.retry_delay),
+            Duration::from_secs(self.retry_max_delay),
+        )
+    }
 
-    let opt = Opt::from_args();
-    create_destination_if_required(opt.destination.clone())?;
+    fn options(&self) -> anyhow::Result<DownloadOptions> {
+        let Formats(formats) = &self.format;
+        let Some((&format, extra_formats)) = formats.split_first() else {
+            return Err(anyhow::anyhow!("No format given"));
+        };
+        for output in &self.format_destinations {
+            if !extra_formats.contains(&output.format) {
+                return Err(anyhow::anyhow!(
+                    "--format-destination is for the formats after the first one, {} isn't one of them",
+                    output.format.extension()
+                ));
+            }
+        }
 
-    if opt.tracks.is_empty() {
-        eprintln!("No tracks provided");
-        std::process::exit(1);
+        let throttle = Throttle::new(self.max_requests, self.limit_rate, self.pause);
+        let options = DownloadOptions {
+            use_alternatives: self.use_alternatives,
+            retry: self.retry_policy().with_throttle(throttle.clone()),
+            throttle,
+            direct: self.direct,
+            quality: self.quality,
+            timeouts: Timeouts {
+                load: Duration::from_secs(self.load_timeout),
+                stall: Duration::from_secs(self.stall_timeout),
+                duration_factor: self.timeout_factor,
+            },
+            length_tolerance: Duration::from_secs(self.length_tolerance),
+            hooks: Hooks {
+                on_track_complete: self.on_track_complete.clone(),
+                on_complete: self.on_complete.clone(),
+                timeout: Duration::from_secs(self.hook_timeout),
+            },
+            single_file: self.single_file,
+            space_check: self.space_check,
+            ..DownloadOptions::new(self.destination.clone(), self.parallel, format, self.force)
+        };
+        let extra_outputs = extra_formats
+            .iter()
+            .map(|&format| ExtraOutput {
+                format,
+                destination: self
+                    .format_destinations
+                    .iter()
+                    .find(|output| output.format == format)
+                    .map_or(&options.destination, |output| &output.destination)
+                    .clone(),
+            })
+            .collect();
+        Ok(DownloadOptions {
+            extra_outputs,
+            ..options
+        })
     }
 
+    /// The options of the commands that keep track of the files they write, which only know
+    /// about a single format.
+    fn single_format_options(&self, command: &str) -> anyhow::Result<DownloadOptions> {
+        let options = self.options()?;
+        if !options.extra_outputs.is_empty() {
+            return Err(anyhow::anyhow!("{} only supports a single --format", command));
+        }
+        Ok(options)
+    }
+}
+
+pub fn create_destination_if_required(destination: Option<String>) -> anyhow::Result<()> {
+    if let Some(destination) = destination
+        && !std::path::Path::new(&destination).exists()
+    {
+        tracing::info!("Creating destination directory: {}", destination);
+        std::fs::create_dir_all(destination)?;
+    }
+    Ok(())
+}
+
+async fn download(args: DownloadArgs) -> anyhow::Result<()> {
+    let inputs = args.inputs()?;
+    create_destination_if_required(args.settings.destination.clone())?;
+
+    let options = args.settings.options()?;
     let session = create_session().await?;
+    let groups = get_track_groups(inputs, &session, &options.retry).await?;
+
+    let shutdown = Shutdown::new();
+    shutdown.listen_for_ctrl_c();
+
+    let downloader = Downloader::new(session).with_shutdown(shutdown);
+    let report = downloader.download(groups, &options).await?;
+    report.print_summary(options.quality);
+    if let Some(path) = &args.report {
+        report.write_to_file(path)?;
+    }
+    Ok(())
+}
 
-    let track = get_tracks(opt.tracks, &session).await?;
+async fn list(args: DownloadArgs, output: ListFormat) -> anyhow::Result<()> {
+    let inputs = args.inputs()?;
+    let options = args.settings.options()?;
+    let session = create_session().await?;
+    let tracks = get_tracks(inputs, &session, &options.retry).await?;
 
     let downloader = Downloader::new(session);
-    downloader
-        .download_tracks(
-            track,
-            &DownloadOptions::new(opt.destination, opt.parallel, opt.format, opt.force),
-        )
-        .await
+    let plan = downloader.plan_tracks(tracks, &options).await?;
+    write_plan(&plan, output, std::io::stdout().lock())
+}
+
+async fn verify(
+    directory: Option<PathBuf>,
+    tolerance: Duration,
+    output: ListFormat,
+) -> anyhow::Result<()> {
+    let directory = directory.map_or_else(std::env::current_dir, Ok)?;
+    let files =
+        tokio::task::spawn_blocking(move || verify_library(&directory, tolerance)).await??;
+    write_verification(&files, output, std::io::stdout().lock())?;
+
+    let failed = files.iter().filter(|file| !file.is_ok()).count();
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "{} files are incomplete or unreadable",
+            failed
+        ));
+    }
+    Ok(())
+}
+
+async fn retag(directory: Option<PathBuf>, options: RetagOptions) -> anyhow::Result<()> {
+    let directory = directory.map_or_else(std::env::current_dir, Ok)?;
+    let session = create_session().await?;
+    let files = retag_library(&session, &directory, &options).await?;
+    write_changes(&files, std::io::stdout().lock())?;
+
+    let count = |status| files.iter().filter(|file| file.status == status).count();
+    println!(
+        "{} {}, up to date {}, without a Spotify ID {}, failed {}",
+        if options.dry_run { "Would retag" } else { "Retagged" },
+        count(RetagStatus::Retagged),
+        count(RetagStatus::UpToDate),
+        count(RetagStatus::Unknown),
+        count(RetagStatus::Failed)
+    );
+    Ok(())
+}
+
+async fn convert(
+    directory: Option<PathBuf>,
+    output: PathBuf,
+    options: ConvertOptions,
+) -> anyhow::Result<()> {
+    let directory = directory.map_or_else(std::env::current_dir, Ok)?;
+    let shutdown = Shutdown::new();
+    shutdown.listen_for_ctrl_c();
+
+    let files = convert_library(&directory, &output, &options, &shutdown).await?;
+    for file in files.iter().filter(|file| file.status == ConvertStatus::Failed) {
+        println!(
+            "{}\n  failed: {}",
+            file.path.display(),
+            file.reason.as_deref().unwrap_or_default()
+        );
+    }
+
+    let count = |status| files.iter().filter(|file| file.status == status).count();
+    println!(
+        "Converted {}, copied {}, already converted {}, failed {}",
+        count(ConvertStatus::Converted),
+        count(ConvertStatus::Copied),
+        count(ConvertStatus::Skipped),
+        count(ConvertStatus::Failed)
+    );
+    Ok(())
+}
+
+async fn serve(
+    listen: SocketAddr,
+    jobs_file: Option<PathBuf>,
+    settings: DownloadSettings,
+) -> anyhow::Result<()> {
+    let options = settings.options()?;
+    create_destination_if_required(settings.destination.clone())?;
+    let jobs_file = jobs_file.map_or_else(default_jobs_path, Ok)?;
+    let queue = JobQueue::load(jobs_file)?;
+    let session = create_session().await?;
+
+    let stop = CancellationToken::new();
+    let stopping = stop.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        eprintln!("Stopping, the job in progress will be resumed on the next start");
+        stopping.cancel();
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+
+    spotify_dl::serve::serve(listen, queue, session, options, stop).await
+}
+
+async fn watch(
+    playlists: Vec<String>,
+    watch: WatchOptions,
+    settings: DownloadSettings,
+) -> anyhow::Result<()> {
+    let options = settings.single_format_options("watch")?;
+    create_destination_if_required(settings.destination.clone())?;
+    let session = create_session().await?;
+
+    let shutdown = Shutdown::new();
+    shutdown.listen_for_ctrl_c();
+
+    let watcher = Watcher::new(session, options, watch)?.with_shutdown(shutdown);
+    watcher.run(playlists).await
+}
+
+async fn mirror(
+    playlist: String,
+    trash: Option<PathBuf>,
+    settings: DownloadSettings,
+) -> anyhow::Result<()> {
+    let options = settings.single_format_options("mirror")?;
+    create_destination_if_required(settings.destination.clone())?;
+    let session = create_session().await?;
+
+    let shutdown = Shutdown::new();
+    shutdown.listen_for_ctrl_c();
+
+    let mirror = Mirror::new(session, options, trash).with_shutdown(shutdown);
+    let summary = mirror.sync(&playlist).await?;
+    println!(
+        "Mirror updated: downloaded {}, renamed {}, retagged {}, moved to the trash {}, failed {}",
+        summary.downloaded, summary.renamed, summary.retagged, summary.trashed, summary.failed
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    log::configure_logger()?;
+
+    let opt = Opt::from_args();
+
+    let result = match opt.command {
+        Some(Command::List { download, output }) => list(download, output).await,
+        Some(Command::Verify {
+            directory,
+            length_tolerance,
+            output,
+        }) => verify(directory, Duration::from_secs(length_tolerance), output).await,
+        Some(Command::Retag {
+            directory,
+            dry_run,
+            parallel,
+        }) => {
+            let options = RetagOptions {
+                retry: RetryPolicy::default(),
+                parallel,
+                dry_run,
+            };
+            retag(directory, options).await
+        }
+        Some(Command::Convert {
+            directory,
+            output,
+            format,
+            parallel,
+            force,
+        }) => {
+            let options = ConvertOptions {
+                format,
+                parallel,
+                force,
+            };
+            convert(directory, output, options).await
+        }
+        Some(Command::Serve {
+            listen,
+            jobs_file,
+            settings,
+        }) => serve(listen, jobs_file, settings).await,
+        Some(Command::Watch {
+            playlists,
+            interval,
+            once,
+            removed,
+            settings,
+        }) => {
+            let options = WatchOptions {
+                interval: Duration::from_secs(interval * 60),
+                removed,
+                once,
+            };
+            watch(playlists, options, settings).await
+        }
+        Some(Command::Mirror {
+            playlist,
+            trash,
+            settings,
+        }) => mirror(playlist, trash, settings).await,
+        None if opt.dry_run => list(opt.download, ListFormat::Table).await,
+        None => download(opt.download).await,
+    };
+
+    // The logger writes from a background thread, make sure the last lines make it to the file
+    log::flush_logger();
+    result
 }
@@ -0,0 +1,502 @@
+//! Mirroring a playlist into a directory.
+//!
+//! The directory holds exactly the tracks of the playlist, named after their position in it.
+//! Every sync downloads the tracks added since the last one, moves the removed ones to the
+//! trash, renames the files whose position changed and updates the tags of the tracks
+//! renamed or retagged upstream, without downloading their audio again. What was mirrored is
+//! kept in a state file in the directory, files the mirror doesn't know about are left alone.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use futures::StreamExt;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyItemType;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::download::DownloadOptions;
+use crate::download::Downloader;
+use crate::encoder::Format;
+use crate::encoder::tags::update_tags;
+use crate::report::TrackStatus;
+use crate::shutdown::Shutdown;
+use crate::track::Playlist;
+use crate::track::Track;
+use crate::track::TrackGroup;
+use crate::track::TrackMetadata;
+use crate::uri::SessionLinkResolver;
+use crate::uri::resolve_uri_or_url;
+
+/// The state of the mirror, in the mirrored directory.
+const STATE_FILE: &str = ".spotify-dl-mirror.json";
+/// Where removed tracks go unless told otherwise, in the mirrored directory.
+const DEFAULT_TRASH_DIR: &str = ".trash";
+/// Where new tracks are downloaded before they are moved into place, in the mirrored directory.
+const STAGING_DIR: &str = ".spotify-dl-staging";
+/// File names are prefixed with the position in the playlist, padded to at least this.
+const MIN_POSITION_WIDTH: usize = 3;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MirrorState {
+    /// The playlist mirrored in the directory.
+    uri: String,
+    /// By track URI.
+    tracks: BTreeMap<String, MirroredTrack>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct MirroredTrack {
+    /// The name of the file in the directory.
+    file: String,
+    /// The tags as they were written, to notice changes upstream.
+    title: String,
+    artists: Vec<String>,
+    album: String,
+}
+
+impl MirroredTrack {
+    fn new(file: String, metadata: &TrackMetadata) -> Self {
+        MirroredTrack {
+            file,
+            title: metadata.track_name.clone(),
+            artists: metadata.artists.iter().map(|a| a.name.clone()).collect(),
+            album: metadata.album.name.clone(),
+        }
+    }
+
+    fn same_tags(&self, metadata: &TrackMetadata) -> bool {
+        let current = MirroredTrack::new(self.file.clone(), metadata);
+        *self == current
+    }
+}
+
+/// What a sync did.
+#[derive(Debug, Default)]
+pub struct MirrorSummary {
+    pub downloaded: usize,
+    pub renamed: usize,
+    pub retagged: usize,
+    pub trashed: usize,
+    pub failed: usize,
+}
+
+pub struct Mirror {
+    session: Session,
+    options: DownloadOptions,
+    trash: PathBuf,
+    shutdown: Shutdown,
+}
+
+impl Mirror {
+    /// Mirrors into the destination of `options`, moving removed tracks to `trash` or to
+    /// `.trash` in the destination.
+    pub fn new(session: Session, options: DownloadOptions, trash: Option<PathBuf>) -> Self {
+        let trash = trash.unwrap_or_else(|| options.destination.join(DEFAULT_TRASH_DIR));
+        Mirror {
+            session,
+            options,
+            trash,
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    /// Lets `shutdown` stop the sync, after the downloads in progress.
+    pub fn with_shutdown(self, shutdown: Shutdown) -> Self {
+        Mirror { shutdown, ..self }
+    }
+
+    /// Brings the directory in line with the playlist as it is now.
+    pub async fn sync(&self, playlist: &str) -> Result<MirrorSummary> {
+        let resolver = SessionLinkResolver::new(self.session.clone());
+        let id = resolve_uri_or_url(playlist, &resolver).await?;
+        if id.item_type != SpotifyItemType::Playlist {
+            return Err(anyhow::anyhow!("{} is not a playlist", playlist));
+        }
+        let uri = id.to_uri()?;
+
+        let mut state = self.load_state()?;
+        if state.uri.is_empty() {
+            state.uri = uri.clone();
+        } else if state.uri != uri {
+            return Err(anyhow::anyhow!(
+                "{} is a mirror of {}, not of {}",
+                self.options.destination.display(),
+                state.uri,
+                uri
+            ));
+        }
+
+        let contents = Playlist::from_id(id)
+            .contents(&self.session, &self.options.retry)
+            .await?;
+        // A track can only be mirrored once, at its first position
+        let mut seen = HashSet::new();
+        let tracks: Vec<Track> = contents
+            .tracks
+            .into_iter()
+            .filter(|track| seen.insert(track.id))
+            .collect();
+        let in_playlist: HashSet<String> = tracks
+            .iter()
+            .filter_map(|track| track.id.to_uri().ok())
+            .collect();
+        println!("Mirroring {} ({} tracks)", contents.name, tracks.len());
+
+        let width = tracks.len().to_string().len().max(MIN_POSITION_WIDTH);
+        let metadata: Vec<(String, Result<TrackMetadata>)> = futures::stream::iter(tracks)
+            .map(|track| async move {
+                let uri = track.id.to_uri().unwrap_or_default();
+                (
+                    uri,
+                    track.metadata(&self.session, &self.options.retry).await,
+                )
+            })
+            .buffered(self.options.parallel)
+            .collect()
+            .await;
+
+        let mut summary = MirrorSummary::default();
+        let mut wanted = Vec::new();
+        for (position, (uri, metadata)) in metadata.into_iter().enumerate() {
+            match metadata {
+                Ok(metadata) => {
+                    let name = format!("{:0width$} - {}", position + 1, metadata, width = width);
+                    wanted.push((uri, name, metadata));
+                }
+                Err(e) => {
+                    // Whatever was mirrored for it is kept until it can be looked up again
+                    tracing::warn!("Failed to get metadata for {}: {:?}", uri, e);
+                    summary.failed += 1;
+                }
+            }
+        }
+
+        self.trash_removed(&mut state, &in_playlist, &mut summary)?;
+        self.save_state(&state)?;
+        self.update_existing(&mut state, &wanted, &mut summary)
+            .await?;
+        self.save_state(&state)?;
+        self.download_missing(&mut state, &wanted, &mut summary)
+            .await?;
+        self.save_state(&state)?;
+        Ok(summary)
+    }
+
+    fn trash_removed(
+        &self,
+        state: &mut MirrorState,
+        in_playlist: &HashSet<String>,
+        summary: &mut MirrorSummary,
+    ) -> Result<()> {
+        let removed: Vec<String> = state
+            .tracks
+            .keys()
+            .filter(|uri| !in_playlist.contains(*uri))
+            .cloned()
+            .collect();
+        for uri in removed {
+            let Some(track) = state.tracks.remove(&uri) else {
+                continue;
+            };
+            let path = self.options.destination.join(&track.file);
+            if !path.exists() {
+                continue;
+            }
+            std::fs::create_dir_all(&self.trash)?;
+            let trashed = unused_path(&self.trash, &track.file);
+            std::fs::rename(&path, &trashed)?;
+            tracing::info!("Moved {} to the trash as {}", track.file, trashed.display());
+            summary.trashed += 1;
+        }
+        Ok(())
+    }
+
+    /// Renames the files whose position changed and retags the tracks changed upstream.
+    async fn update_existing(
+        &self,
+        state: &mut MirrorState,
+        wanted: &[(String, String, TrackMetadata)],
+        summary: &mut MirrorSummary,
+    ) -> Result<()> {
+        let mut renames = Vec::new();
+        for (uri, name, metadata) in wanted {
+            let Some(track) = state.tracks.get_mut(uri) else {
+                continue;
+            };
+            let path = self.options.destination.join(&track.file);
+            let Some(format) = Format::from_path(&path).filter(|_| path.exists()) else {
+                continue;
+            };
+
+            if !track.same_tags(metadata) {
+                let tags = metadata.tags().await?;
+                update_tags(path.to_string_lossy().to_string(), &tags, format).await?;
+                *track = MirroredTrack::new(track.file.clone(), metadata);
+                tracing::info!("Retagged {}", track.file);
+                summary.retagged += 1;
+            }
+
+            let file = format!("{}.{}", name, format.extension());
+            if track.file != file {
+                renames.push((uri.clone(), file));
+            }
+        }
+        self.rename(state, renames, summary)
+    }
+
+    /// Renames the files of the tracks to the new names, by track URI.
+    fn rename(
+        &self,
+        state: &mut MirrorState,
+        renames: Vec<(String, String)>,
+        summary: &mut MirrorSummary,
+    ) -> Result<()> {
+        // Through a temporary name first, so swapping two positions doesn't clash
+        let mut moved = Vec::new();
+        for (uri, file) in renames {
+            let track = &state.tracks[&uri];
+            let temporary = format!("{}.renaming", track.file);
+            std::fs::rename(
+                self.options.destination.join(&track.file),
+                self.options.destination.join(&temporary),
+            )?;
+            moved.push((uri, temporary, file));
+        }
+        for (uri, temporary, file) in moved {
+            let target = self.options.destination.join(&file);
+            let track = state
+                .tracks
+                .get_mut(&uri)
+                .expect("renamed tracks are mirrored");
+            if target.exists() {
+                tracing::warn!("Not renaming {}, {} already exists", track.file, file);
+                std::fs::rename(
+                    self.options.destination.join(&temporary),
+                    self.options.destination.join(&track.file),
+                )?;
+                continue;
+            }
+            std::fs::rename(self.options.destination.join(&temporary), &target)?;
+            tracing::info!("Renamed {} to {}", track.file, file);
+            track.file = file;
+            summary.renamed += 1;
+        }
+        Ok(())
+    }
+
+    /// Downloads the tracks that aren't in the directory yet, and names them after their
+    /// position.
+    ///
+    /// They are downloaded to a staging directory under their id first, so tracks named alike
+    /// don't clash and a file already in the directory is never mistaken for a download.
+    async fn download_missing(
+        &self,
+        state: &mut MirrorState,
+        wanted: &[(String, String, TrackMetadata)],
+        summary: &mut MirrorSummary,
+    ) -> Result<()> {
+        let missing: Vec<&(String, String, TrackMetadata)> = wanted
+            .iter()
+            .filter(|(uri, _, _)| {
+                state
+                    .tracks
+                    .get(uri)
+                    .is_none_or(|track| !self.options.destination.join(&track.file).exists())
+            })
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let groups = vec![TrackGroup {
+            album: false,
+            tracks: missing
+                .iter()
+                .map(|(_, _, metadata)| Track::from_id(metadata.id))
+                .collect(),
+        }];
+        let staging = self.options.destination.join(STAGING_DIR);
+        let options = DownloadOptions {
+            destination: staging.clone(),
+            name_by_id: true,
+            ..self.options.clone()
+        };
+        let downloader = Downloader::new(self.session.clone())
+            .with_shutdown(self.shutdown.clone())
+            .with_metadata(missing.iter().map(|(_, _, metadata)| metadata.clone()));
+        let report = downloader.download(groups, &options).await?;
+        report.print_summary(self.options.quality);
+
+        let reports: HashMap<&str, _> = report
+            .tracks
+            .iter()
+            .map(|track| (track.uri.as_str(), track))
+            .collect();
+        for (uri, name, metadata) in missing {
+            let Some(report) = reports.get(uri.as_str()) else {
+                continue;
+            };
+            if !matches!(
+                report.status,
+                TrackStatus::Downloaded | TrackStatus::Skipped
+            ) {
+                summary.failed += 1;
+                continue;
+            }
+            let file = format!("{}.{}", name, self.options.format.extension());
+            let target = self.options.destination.join(&file);
+            if target.exists() {
+                tracing::warn!("Not renaming {}, {} already exists", report.name, file);
+                summary.failed += 1;
+                continue;
+            }
+            if let Err(e) = std::fs::rename(&report.path, &target) {
+                tracing::warn!("Failed to move {} into place: {}", report.name, e);
+                summary.failed += 1;
+                continue;
+            }
+            state
+                .tracks
+                .insert(uri.clone(), MirroredTrack::new(file, metadata));
+            self.save_state(state)?;
+            summary.downloaded += 1;
+        }
+        // Only goes once everything in it was moved into place
+        let _ = std::fs::remove_dir(&staging);
+        Ok(())
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.options.destination.join(STATE_FILE)
+    }
+
+    fn load_state(&self) -> Result<MirrorState> {
+        match std::fs::read(self.state_path()) {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(MirrorState::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Saves the state, the file is replaced at once so it is never left half written.
+    fn save_state(&self, state: &MirrorState) -> Result<()> {
+        let path = self.state_path();
+        let temporary = path.with_extension("json.tmp");
+        std::fs::write(&temporary, serde_json::to_vec_pretty(state)?)?;
+        std::fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+}
+
+/// Where `file` can go in `directory` without replacing anything, numbered like
+/// `file (2).flac` when there already is one by that name.
+fn unused_path(directory: &Path, file: &str) -> PathBuf {
+    let path = directory.join(file);
+    if !path.exists() {
+        return path;
+    }
+    let file = Path::new(file);
+    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = file
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (2..)
+        .map(|number| directory.join(format!("{} ({}){}", stem, number, extension)))
+        .find(|path| !path.exists())
+        .expect("there is always a free number")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(destination: &Path) -> Mirror {
+        let options = DownloadOptions::new(
+            Some(destination.to_string_lossy().to_string()),
+            1,
+            Format::Flac,
+            false,
+        );
+        Mirror::new(Session::new(Default::default(), None), options, None)
+    }
+
+    fn mirrored(file: &str) -> MirroredTrack {
+        MirroredTrack {
+            file: file.to_string(),
+            title: "Song".to_string(),
+            artists: vec!["Artist".to_string()],
+            album: "Album".to_string(),
+        }
+    }
+
+    fn state(tracks: &[(&str, &str)]) -> MirrorState {
+        MirrorState {
+            uri: "spotify:playlist:1".to_string(),
+            tracks: tracks
+                .iter()
+                .map(|(uri, file)| (uri.to_string(), mirrored(file)))
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn swaps_positions() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = |file: &str| directory.path().join(file);
+        std::fs::write(path("001 - Song.flac"), "a").unwrap();
+        std::fs::write(path("002 - Song.flac"), "b").unwrap();
+        let mut state = state(&[("a", "001 - Song.flac"), ("b", "002 - Song.flac")]);
+        let mut summary = MirrorSummary::default();
+
+        mirror(directory.path())
+            .rename(
+                &mut state,
+                vec![
+                    ("a".to_string(), "002 - Song.flac".to_string()),
+                    ("b".to_string(), "001 - Song.flac".to_string()),
+                ],
+                &mut summary,
+            )
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(path("002 - Song.flac")).unwrap(),
+            "a"
+        );
+        assert_eq!(
+            std::fs::read_to_string(path("001 - Song.flac")).unwrap(),
+            "b"
+        );
+        assert_eq!(state.tracks["a"].file, "002 - Song.flac");
+        assert_eq!(state.tracks["b"].file, "001 - Song.flac");
+        assert_eq!(summary.renamed, 2);
+    }
+
+    #[tokio::test]
+    async fn keeps_earlier_trashed_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let mirror = mirror(directory.path());
+        std::fs::create_dir(&mirror.trash).unwrap();
+        std::fs::write(mirror.trash.join("001 - Song.flac"), "earlier").unwrap();
+        std::fs::write(directory.path().join("001 - Song.flac"), "removed").unwrap();
+        let mut state = state(&[("a", "001 - Song.flac")]);
+        let mut summary = MirrorSummary::default();
+
+        mirror
+            .trash_removed(&mut state, &HashSet::new(), &mut summary)
+            .unwrap();
+
+        let trashed = |file: &str| std::fs::read_to_string(mirror.trash.join(file)).unwrap();
+        assert_eq!(trashed("001 - Song.flac"), "earlier");
+        assert_eq!(trashed("001 - Song (2).flac"), "removed");
+        assert!(!directory.path().join("001 - Song.flac").exists());
+        assert!(state.tracks.is_empty());
+        assert_eq!(summary.trashed, 1);
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::utils::format_duration_ms;
+use crate::utils::write_columns;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ListFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+impl FromStr for ListFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "table" => Ok(ListFormat::Table),
+            "json" => Ok(ListFormat::Json),
+            "csv" => Ok(ListFormat::Csv),
+            _ => Err(anyhow::anyhow!("Unsupported list format")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlannedAction {
+    Download,
+    Skip,
+    Unavailable,
+}
+
+impl PlannedAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PlannedAction::Download => "download",
+            PlannedAction::Skip => "skip",
+            PlannedAction::Unavailable => "unavailable",
+        }
+    }
+}
+
+/// What a download run would do with a single track.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedTrack {
+    pub uri: String,
+    pub name: String,
+    pub path: PathBuf,
+    pub duration_ms: i32,
+    pub available: bool,
+    pub reason: Option<String>,
+    pub action: PlannedAction,
+}
+
+pub fn write_plan<W: Write>(plan: &[PlannedTrack], format: ListFormat, mut out: W) -> Result<()> {
+    match format {
+        ListFormat::Table => write_table(plan, &mut out)?,
+        ListFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, plan)?;
+            writeln!(out)?;
+        }
+        ListFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for track in plan {
+                writer.serialize(track)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn write_table<W: Write>(plan: &[PlannedTrack], out: &mut W) -> Result<()> {
+    let rows: Vec<[String; 4]> = plan
+        .iter()
+        .map(|track| {
+            let status = match &track.reason {
+                Some(reason) => format!("{} ({})", track.action.as_str(), reason),
+                None => track.action.as_str().to_string(),
+            };
+            [
+                status,
+                format_duration_ms(track.duration_ms.max(0) as u64),
+                track.uri.clone(),
+                track.path.display().to_string(),
+            ]
+        })
+        .collect();
+
+    write_columns(out, ["STATUS", "DURATION", "URI", "PATH"], &rows, &[1])?;
+
+    let total_ms: u64 = plan
+        .iter()
+        .map(|track| track.duration_ms.max(0) as u64)
+        .sum();
+    let count = |action: PlannedAction| plan.iter().filter(|t| t.action == action).count();
+    writeln!(
+        out,
+        "\n{} tracks, {} to download, {} skipped, {} unavailable, total duration {}",
+        plan.len(),
+        count(PlannedAction::Download),
+        count(PlannedAction::Skip),
+        count(PlannedAction::Unavailable),
+        format_duration_ms(total_ms),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,212 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::hooks::HookOutcome;
+use crate::stream::Quality;
+use crate::stream::SourceQuality;
+use crate::track::TrackMetadata;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackStatus {
+    Downloaded,
+    Skipped,
+    Unavailable,
+    Failed,
+    /// Interrupted by a stop or an abort.
+    Cancelled,
+}
+
+impl TrackStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrackStatus::Downloaded => "downloaded",
+            TrackStatus::Skipped => "skipped",
+            TrackStatus::Unavailable => "unavailable",
+            TrackStatus::Failed => "failed",
+            TrackStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// What happened to a single track during a download run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackReport {
+    pub uri: String,
+    pub name: String,
+    pub path: PathBuf,
+    pub status: TrackStatus,
+    pub reason: Option<String>,
+    pub source_codec: Option<String>,
+    pub source_bitrate: Option<u32>,
+    /// The outcome of `--on-track-complete`, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hook: Option<HookOutcome>,
+}
+
+impl TrackReport {
+    pub fn new(metadata: &TrackMetadata, path: PathBuf, status: TrackStatus) -> Self {
+        TrackReport {
+            uri: metadata.id.to_uri().unwrap_or_default(),
+            name: metadata.to_string(),
+            path,
+            status,
+            reason: None,
+            source_codec: None,
+            source_bitrate: None,
+            hook: None,
+        }
+    }
+
+    /// The report of a track whose metadata couldn't be fetched, named after its URI.
+    pub fn unresolved(uri: String, status: TrackStatus) -> Self {
+        TrackReport {
+            name: uri.clone(),
+            uri,
+            path: PathBuf::new(),
+            status,
+            reason: None,
+            source_codec: None,
+            source_bitrate: None,
+            hook: None,
+        }
+    }
+
+    pub fn with_reason<S: Into<String>>(self, reason: S) -> Self {
+        TrackReport {
+            reason: Some(reason.into()),
+            ..self
+        }
+    }
+
+    pub fn with_source(self, source: Option<SourceQuality>) -> Self {
+        TrackReport {
+            source_codec: source.map(|source| source.codec().to_string()),
+            source_bitrate: source.map(|source| source.kbps()),
+            ..self
+        }
+    }
+
+    pub fn with_hook(self, hook: HookOutcome) -> Self {
+        TrackReport {
+            hook: Some(hook),
+            ..self
+        }
+    }
+}
+
+/// The outcome of a download run, one entry per track.
+#[derive(Debug, Default, Serialize)]
+pub struct DownloadReport {
+    pub tracks: Vec<TrackReport>,
+    /// Tracks that were never picked up because the run was stopped.
+    pub not_started: usize,
+    /// The outcome of `--on-complete`, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_complete: Option<HookOutcome>,
+}
+
+impl DownloadReport {
+    pub fn new(tracks: Vec<TrackReport>) -> Self {
+        DownloadReport {
+            tracks,
+            not_started: 0,
+            on_complete: None,
+        }
+    }
+
+    pub fn with_not_started(self, not_started: usize) -> Self {
+        DownloadReport {
+            not_started,
+            ..self
+        }
+    }
+
+    pub fn count(&self, status: TrackStatus) -> usize {
+        self.tracks
+            .iter()
+            .filter(|track| track.status == status)
+            .count()
+    }
+
+    /// Tracks downloaded from a lower quality file than the one asked for.
+    pub fn lower_quality(&self, quality: Quality) -> impl Iterator<Item = &TrackReport> {
+        self.tracks.iter().filter(move |track| {
+            track
+                .source_bitrate
+                .is_some_and(|bitrate| bitrate < quality.kbps())
+        })
+    }
+
+    pub fn print_summary(&self, quality: Quality) {
+        println!(
+            "Downloaded {}, skipped {}, unavailable {}, failed {}",
+            self.count(TrackStatus::Downloaded),
+            self.count(TrackStatus::Skipped),
+            self.count(TrackStatus::Unavailable),
+            self.count(TrackStatus::Failed),
+        );
+        let cancelled = self.count(TrackStatus::Cancelled);
+        if cancelled > 0 || self.not_started > 0 {
+            println!(
+                "{}",
+                console::style(format!(
+                    "Interrupted: cancelled {}, not started {}",
+                    cancelled, self.not_started
+                ))
+                .yellow()
+            );
+        }
+        for track in self.lower_quality(quality) {
+            println!(
+                "{}",
+                console::style(format!(
+                    "Lower quality source ({} {} kbps): {}",
+                    track.source_codec.as_deref().unwrap_or_default(),
+                    track.source_bitrate.unwrap_or_default(),
+                    track.name
+                ))
+                .yellow()
+            );
+        }
+        let hooks = self.tracks.iter().filter_map(|track| Some((&track.name, track.hook.as_ref()?)));
+        for (name, hook) in hooks.filter(|(_, hook)| !hook.is_ok()) {
+            println!(
+                "{}",
+                console::style(format!(
+                    "Hook failed for {}: {}",
+                    name,
+                    hook.error.as_deref().unwrap_or_default()
+                ))
+                .yellow()
+            );
+        }
+        if let Some(hook) = self.on_complete.as_ref().filter(|hook| !hook.is_ok()) {
+            println!(
+                "{}",
+                console::style(format!(
+                    "Completion hook failed: {}",
+                    hook.error.as_deref().unwrap_or_default()
+                ))
+                .yellow()
+            );
+        }
+    }
+
+    pub fn with_on_complete(self, on_complete: Option<HookOutcome>) -> Self {
+        DownloadReport {
+            on_complete,
+            ..self
+        }
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
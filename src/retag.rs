@@ -0,0 +1,219 @@
+//! Rewriting the tags of a library downloaded by spotify-dl, without downloading it again.
+//!
+//! Files are matched to their track through the URI stored in them on download, so files
+//! downloaded by older versions are left alone.
+
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures::StreamExt;
+use librespot::core::session::Session;
+
+use crate::download::SOURCE_BITRATE_TAG;
+use crate::download::SOURCE_CODEC_TAG;
+use crate::download::track_tags;
+use crate::encoder::Format;
+use crate::encoder::tags::TRACK_URI_TAG;
+use crate::encoder::tags::Tags;
+use crate::encoder::tags::read_extra_tag;
+use crate::encoder::tags::read_gapless_info;
+use crate::encoder::tags::update_all_tags;
+use crate::retry::RetryPolicy;
+use crate::track::Track;
+use crate::verify::find_audio_files;
+
+/// Tags about how the file was downloaded, which can't be fetched again.
+const KEPT_TAGS: [&str; 2] = [SOURCE_CODEC_TAG, SOURCE_BITRATE_TAG];
+
+#[derive(Debug, Clone)]
+pub struct RetagOptions {
+    pub retry: RetryPolicy,
+    pub parallel: usize,
+    /// Only work out the changes, without writing them.
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RetagStatus {
+    /// The tags changed, and were rewritten unless it is a dry run.
+    Retagged,
+    UpToDate,
+    /// The file has no track URI, it was downloaded by an older version.
+    Unknown,
+    Failed,
+}
+
+/// A tag whose value changes, `None` when it isn't set.
+#[derive(Debug, Clone)]
+pub struct TagChange {
+    pub tag: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// The result of retagging a single file of the library.
+#[derive(Debug, Clone)]
+pub struct RetaggedFile {
+    pub path: PathBuf,
+    pub status: RetagStatus,
+    pub changes: Vec<TagChange>,
+    pub reason: Option<String>,
+}
+
+/// Fetches the metadata of every downloaded file under `directory` again and rewrites the tags
+/// of the files where it changed.
+pub async fn retag_library(
+    session: &Session,
+    directory: &Path,
+    options: &RetagOptions,
+) -> Result<Vec<RetaggedFile>> {
+    let mut files = Vec::new();
+    find_audio_files(directory, &mut files)?;
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let retagged = futures::stream::iter(files)
+        .map(|(path, format)| async move {
+            match retag_file(session, &path, format, options).await {
+                Ok((status, changes)) => RetaggedFile {
+                    path,
+                    status,
+                    changes,
+                    reason: None,
+                },
+                Err(e) => {
+                    tracing::warn!("Failed to retag {}: {:?}", path.display(), e);
+                    RetaggedFile {
+                        path,
+                        status: RetagStatus::Failed,
+                        changes: Vec::new(),
+                        reason: Some(e.to_string()),
+                    }
+                }
+            }
+        })
+        .buffered(options.parallel)
+        .collect()
+        .await;
+    Ok(retagged)
+}
+
+async fn retag_file(
+    session: &Session,
+    path: &Path,
+    format: Format,
+    options: &RetagOptions,
+) -> Result<(RetagStatus, Vec<TagChange>)> {
+    let Some(uri) = read_extra_tag(path, TRACK_URI_TAG, format)? else {
+        return Ok((RetagStatus::Unknown, Vec::new()));
+    };
+    let metadata = Track::new(&uri)?.metadata(session, &options.retry).await?;
+
+    let mut tags = track_tags(&metadata).await?;
+    if tags.album_cover.is_none() {
+        // Failing to fetch the cover is no reason to drop the one the file has
+        let current = audiotags::Tag::new().read_from_path(path)?;
+        tags.album_cover = current
+            .album_cover()
+            .map(|cover| Bytes::copy_from_slice(cover.data));
+    }
+    for key in KEPT_TAGS {
+        if let Some(value) = read_extra_tag(path, key, format)? {
+            tags.extra.push((key.to_string(), value));
+        }
+    }
+    if format == Format::Mp3 {
+        tags.gapless = read_gapless_info(path)?;
+    }
+
+    let changes = changes(path, &tags, format)?;
+    if changes.is_empty() {
+        return Ok((RetagStatus::UpToDate, changes));
+    }
+    if !options.dry_run {
+        update_all_tags(path.to_string_lossy().to_string(), &tags, format).await?;
+        tracing::info!("Retagged {}", path.display());
+    }
+    Ok((RetagStatus::Retagged, changes))
+}
+
+/// What writing `tags` would change in the file.
+fn changes(path: &Path, tags: &Tags, format: Format) -> Result<Vec<TagChange>> {
+    let current = audiotags::Tag::new().read_from_path(path)?;
+    let mut changes = Vec::new();
+    let mut compare = |tag: &str, old: Option<String>, new: Option<String>| {
+        if old != new {
+            changes.push(TagChange {
+                tag: tag.to_string(),
+                old,
+                new,
+            });
+        }
+    };
+
+    compare(
+        "title",
+        current.title().map(str::to_string),
+        Some(tags.title.clone()),
+    );
+    // Only the first artist is stored
+    compare(
+        "artist",
+        current.artist().map(str::to_string),
+        tags.artists.first().cloned(),
+    );
+    compare(
+        "album",
+        current.album_title().map(str::to_string),
+        Some(tags.album_title.clone()),
+    );
+    for (key, value) in &tags.extra {
+        compare(key, read_extra_tag(path, key, format)?, Some(value.clone()));
+    }
+
+    let old_cover = current.album_cover().map(|cover| cover.data.to_vec());
+    let new_cover = tags.album_cover.as_ref().map(|cover| cover.to_vec());
+    if old_cover != new_cover {
+        let size = |cover: Option<Vec<u8>>| cover.map(|cover| format!("{} bytes", cover.len()));
+        changes.push(TagChange {
+            tag: "cover".to_string(),
+            old: size(old_cover),
+            new: size(new_cover),
+        });
+    }
+    Ok(changes)
+}
+
+/// Writes the changes to every file that has any, as a diff.
+pub fn write_changes<W: Write>(files: &[RetaggedFile], mut out: W) -> Result<()> {
+    let value = |value: &Option<String>| match value {
+        Some(value) => format!("{:?}", value),
+        None => "(none)".to_string(),
+    };
+    for file in files {
+        match file.status {
+            RetagStatus::Retagged => {
+                writeln!(out, "{}", file.path.display())?;
+                for change in &file.changes {
+                    writeln!(
+                        out,
+                        "  {}: {} -> {}",
+                        change.tag,
+                        value(&change.old),
+                        value(&change.new)
+                    )?;
+                }
+            }
+            RetagStatus::Failed => writeln!(
+                out,
+                "{}\n  failed: {}",
+                file.path.display(),
+                file.reason.as_deref().unwrap_or_default()
+            )?,
+            RetagStatus::UpToDate | RetagStatus::Unknown => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use librespot::core::error::ErrorKind;
+use librespot::metadata::MetadataError;
+
+use crate::stream::StreamError;
+use crate::throttle::Throttle;
+
+/// Whether an error is worth retrying.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ErrorClass {
+    /// Network hiccups and server errors, which usually go away by themselves.
+    Transient,
+    /// Spotify is throttling our requests.
+    RateLimited,
+    /// Unavailable content, authentication or invalid requests, retrying won't help.
+    Permanent,
+}
+
+impl ErrorClass {
+    pub fn is_retryable(&self) -> bool {
+        *self != ErrorClass::Permanent
+    }
+}
+
+pub trait Classify {
+    fn class(&self) -> ErrorClass;
+}
+
+impl Classify for librespot::core::Error {
+    fn class(&self) -> ErrorClass {
+        match self.kind {
+            ErrorKind::ResourceExhausted => ErrorClass::RateLimited,
+            // Unavailable is used both for unplayable items and for unreachable services
+            ErrorKind::Unavailable if self.error.is::<MetadataError>() => ErrorClass::Permanent,
+            ErrorKind::Unknown
+            | ErrorKind::DeadlineExceeded
+            | ErrorKind::Aborted
+            | ErrorKind::Internal
+            | ErrorKind::Unavailable
+            | ErrorKind::DataLoss => ErrorClass::Transient,
+            _ => ErrorClass::Permanent,
+        }
+    }
+}
+
+impl Classify for StreamError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            StreamError::Unavailable(_) | StreamError::Cancelled => ErrorClass::Permanent,
+            StreamError::LoadError(_)
+            | StreamError::DecodeError(_)
+            | StreamError::Timeout(_)
+            | StreamError::Unknown => ErrorClass::Transient,
+        }
+    }
+}
+
+impl Classify for anyhow::Error {
+    fn class(&self) -> ErrorClass {
+        if let Some(error) = self.downcast_ref::<librespot::core::Error>() {
+            error.class()
+        } else if let Some(error) = self.downcast_ref::<StreamError>() {
+            error.class()
+        } else {
+            ErrorClass::Transient
+        }
+    }
+}
+
+/// How many times and how often failed requests to Spotify are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Every attempt waits for the throttle, and throttling errors make it back off.
+    throttle: Throttle,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(30),
+            throttle: Throttle::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, initial_delay: Duration, max_delay: Duration) -> Self {
+        RetryPolicy {
+            max_retries,
+            initial_delay,
+            max_delay,
+            throttle: Throttle::default(),
+        }
+    }
+
+    pub fn with_throttle(self, throttle: Throttle) -> Self {
+        RetryPolicy { throttle, ..self }
+    }
+
+    /// Waits until the throttle lets another request through, for requests not sent by
+    /// [`RetryPolicy::retry`].
+    pub async fn until_ready(&self) {
+        self.throttle.until_ready().await;
+    }
+
+    /// Exponential backoff that gives up straight away on permanent errors.
+    pub fn delay<E: Classify>(&self, attempt: u32, error: &E) -> tryhard::RetryPolicy {
+        match error.class() {
+            ErrorClass::Permanent => return tryhard::RetryPolicy::Break,
+            ErrorClass::RateLimited => self.throttle.rate_limited(),
+            ErrorClass::Transient => {}
+        }
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        tryhard::RetryPolicy::Delay(
+            self.initial_delay
+                .saturating_mul(factor)
+                .min(self.max_delay),
+        )
+    }
+
+    /// Runs `f` until it succeeds, it fails with a permanent error or it runs out of retries.
+    pub async fn retry<T, E, F, Fut>(&self, what: &str, f: F) -> Result<T, E>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Classify + Display + 'static,
+    {
+        let what = what.to_string();
+        let result = tryhard::retry_fn(|| async {
+            self.throttle.until_ready().await;
+            f().await
+        })
+        .retries(self.max_retries)
+        .custom_backoff(|attempt, error: &E| self.delay(attempt, error))
+        .on_retry(|attempt, next_delay, error: &E| {
+            match next_delay {
+                Some(delay) => tracing::warn!(
+                    "Attempt {} to {} failed, retrying in {:?}: {}",
+                    attempt,
+                    what,
+                    delay,
+                    error
+                ),
+                None => tracing::warn!("Giving up trying to {}: {}", what, error),
+            }
+            futures::future::ready(())
+        })
+        .await;
+        if result.is_ok() {
+            self.throttle.succeeded();
+        }
+        result
+    }
+}
@@ -0,0 +1,297 @@
+use std::convert::Infallible;
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures::StreamExt;
+use http::Method;
+use http::Request;
+use http::Response;
+use http::StatusCode;
+use http::header;
+use http_body_util::BodyExt;
+use http_body_util::Full;
+use http_body_util::Limited;
+use http_body_util::StreamBody;
+use http_body_util::combinators::UnsyncBoxBody;
+use hyper::body::Frame;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+
+use crate::serve::jobs::JobEvent;
+use crate::serve::jobs::JobQueue;
+
+type Body = UnsyncBoxBody<Bytes, Infallible>;
+
+/// Requests only ever hold a list of URIs.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Deserialize)]
+struct NewJob {
+    uris: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// Answers requests on `listener` until `stop` is cancelled.
+pub async fn serve(listener: TcpListener, queue: JobQueue, stop: CancellationToken) -> Result<()> {
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            },
+            _ = stop.cancelled() => return Ok(()),
+        };
+
+        let queue = queue.clone();
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| handle(queue.clone(), stop.clone(), request));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle<B>(
+    queue: JobQueue,
+    stop: CancellationToken,
+    request: Request<B>,
+) -> Result<Response<Body>, Infallible>
+where
+    B: hyper::body::Body,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let path = request.uri().path().trim_matches('/').to_string();
+    let segments: Vec<&str> = path.split('/').collect();
+    tracing::debug!("{} /{}", request.method(), path);
+
+    let response = match (request.method().clone(), segments.as_slice()) {
+        (Method::GET, ["jobs"]) => json(StatusCode::OK, &queue.jobs()),
+        (Method::POST, ["jobs"]) => enqueue(&queue, request).await,
+        (Method::GET, ["jobs", id]) => match id.parse().ok().and_then(|id| queue.job(id)) {
+            Some(job) => json(StatusCode::OK, &job),
+            None => error(StatusCode::NOT_FOUND, "No such job"),
+        },
+        (Method::DELETE, ["jobs", id]) => match id.parse() {
+            Ok(id) => cancel(&queue, id),
+            Err(_) => error(StatusCode::NOT_FOUND, "No such job"),
+        },
+        (Method::GET, ["jobs", id, "events"]) => match id.parse() {
+            Ok(id) => events(&queue, stop, id),
+            Err(_) => error(StatusCode::NOT_FOUND, "No such job"),
+        },
+        (_, ["jobs"]) | (_, ["jobs", _]) | (_, ["jobs", _, "events"]) => {
+            error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+        }
+        _ => error(StatusCode::NOT_FOUND, "Not found"),
+    };
+    Ok(response)
+}
+
+async fn enqueue<B>(queue: &JobQueue, request: Request<B>) -> Response<Body>
+where
+    B: hyper::body::Body,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let body = match Limited::new(request.into_body(), MAX_BODY_SIZE)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let new_job = match serde_json::from_slice::<NewJob>(&body) {
+        Ok(new_job) => new_job,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("Invalid job: {}", e)),
+    };
+    if new_job.uris.is_empty() {
+        return error(StatusCode::BAD_REQUEST, "No URIs provided");
+    }
+
+    json(StatusCode::CREATED, &queue.enqueue(new_job.uris))
+}
+
+fn cancel(queue: &JobQueue, id: u64) -> Response<Body> {
+    match queue.job(id) {
+        None => return error(StatusCode::NOT_FOUND, "No such job"),
+        Some(job) if job.status.is_finished() => {
+            return error(StatusCode::CONFLICT, "The job is already finished");
+        }
+        Some(_) => {}
+    }
+    match queue.cancel(id) {
+        Some(job) => json(StatusCode::OK, &job),
+        None => error(StatusCode::NOT_FOUND, "No such job"),
+    }
+}
+
+/// Server-sent events for a job: a `job` event with the job as it is now and whenever its
+/// status changes, and a `track` event with the report of every track once it is done. The
+/// stream ends when the job does.
+fn events(queue: &JobQueue, stop: CancellationToken, id: u64) -> Response<Body> {
+    // Subscribed before looking at the job, so nothing happens in between
+    let receiver = queue.subscribe();
+    let Some(job) = queue.job(id) else {
+        return error(StatusCode::NOT_FOUND, "No such job");
+    };
+
+    let first = server_sent_event("job", &job);
+    let updates = futures::stream::unfold(
+        (receiver, job.status.is_finished()),
+        move |(mut receiver, finished)| async move {
+            if finished {
+                return None;
+            }
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) if event.job_id() == id => event,
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                };
+                return Some(match event {
+                    JobEvent::Status(job) => (
+                        server_sent_event("job", &job),
+                        (receiver, job.status.is_finished()),
+                    ),
+                    JobEvent::Track { report, .. } => {
+                        (server_sent_event("track", &report), (receiver, false))
+                    }
+                });
+            }
+        },
+    );
+    let stream = futures::stream::once(futures::future::ready(first))
+        .chain(updates)
+        .take_until(stop.cancelled_owned())
+        .map(|event| Ok(Frame::data(event)));
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(StreamBody::new(stream).boxed_unsync())
+        // Infallible, the headers are valid
+        .unwrap()
+}
+
+fn server_sent_event<T: Serialize>(event: &str, data: &T) -> Bytes {
+    let data = serde_json::to_string(data).unwrap_or_default();
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+fn json<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)).boxed_unsync())
+        // Infallible, the headers are valid
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    json(
+        status,
+        &ErrorBody {
+            error: message.to_string(),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(queue: &JobQueue, method: Method, path: &str, body: &str) -> StatusCode {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap();
+        let response = handle(queue.clone(), CancellationToken::new(), request)
+            .await
+            .unwrap();
+        response.status()
+    }
+
+    #[tokio::test]
+    async fn routes_requests() {
+        let directory = tempfile::tempdir().unwrap();
+        let queue = JobQueue::load(directory.path().join("jobs.json")).unwrap();
+        let uris = r#"{"uris": ["spotify:track:0000000000000000000001"]}"#;
+
+        assert_eq!(
+            request(&queue, Method::GET, "/jobs", "").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            request(&queue, Method::POST, "/jobs", uris).await,
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            request(&queue, Method::POST, "/jobs", r#"{"uris": []}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            request(&queue, Method::GET, "/jobs/1", "").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            request(&queue, Method::GET, "/jobs/2", "").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            request(&queue, Method::GET, "/jobs/first", "").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            request(&queue, Method::GET, "/tracks", "").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            request(&queue, Method::PUT, "/jobs", "").await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            request(&queue, Method::POST, "/jobs/1/events", "").await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+
+    #[tokio::test]
+    async fn cancels_only_unfinished_jobs() {
+        let directory = tempfile::tempdir().unwrap();
+        let queue = JobQueue::load(directory.path().join("jobs.json")).unwrap();
+        let job = queue.enqueue(vec!["spotify:track:0000000000000000000001".to_string()]);
+        let path = format!("/jobs/{}", job.id);
+
+        assert_eq!(
+            request(&queue, Method::DELETE, &path, "").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            request(&queue, Method::DELETE, &path, "").await,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            request(&queue, Method::DELETE, "/jobs/2", "").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
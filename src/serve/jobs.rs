@@ -0,0 +1,516 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use librespot::core::session::Session;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::Notify;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::download::DownloadOptions;
+use crate::download::Downloader;
+use crate::report::DownloadReport;
+use crate::report::TrackReport;
+use crate::session::create_session;
+use crate::shutdown::Shutdown;
+use crate::track::get_track_groups;
+
+/// How many events a slow listener can fall behind before it misses some.
+const EVENTS_CAPACITY: usize = 256;
+/// How many finished jobs are kept, the oldest ones are forgotten.
+const MAX_FINISHED_JOBS: usize = 100;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+/// A list of URIs to download, as one run of the downloader.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub uris: Vec<String>,
+    pub status: JobStatus,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// Known once the URIs are resolved.
+    pub total_tracks: Option<usize>,
+    /// The tracks done so far.
+    pub tracks: Vec<TrackReport>,
+    pub not_started: usize,
+    pub error: Option<String>,
+}
+
+/// Sent to the listeners of [`JobQueue::subscribe`] whenever a job changes.
+#[derive(Debug, Clone)]
+pub enum JobEvent {
+    /// The job was queued, started or finished.
+    Status(Job),
+    /// A track of the job is done.
+    Track { job: u64, report: TrackReport },
+}
+
+impl JobEvent {
+    pub fn job_id(&self) -> u64 {
+        match self {
+            JobEvent::Status(job) => job.id,
+            JobEvent::Track { job, .. } => *job,
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    jobs: Vec<Job>,
+    next_id: u64,
+    #[serde(skip)]
+    running: Option<RunningJob>,
+}
+
+impl State {
+    fn prune(&mut self) {
+        let finished = self
+            .jobs
+            .iter()
+            .filter(|job| job.status.is_finished())
+            .count();
+        let mut excess = finished.saturating_sub(MAX_FINISHED_JOBS);
+        self.jobs.retain(|job| {
+            if excess > 0 && job.status.is_finished() {
+                excess -= 1;
+                return false;
+            }
+            true
+        });
+    }
+}
+
+struct RunningJob {
+    id: u64,
+    shutdown: Shutdown,
+    cancelled: bool,
+}
+
+/// The jobs of the daemon, run one after the other.
+///
+/// The queue is saved to a file after every change by [`JobQueue::save_changes`]. Jobs that
+/// were queued or running when the daemon stopped are run again when it starts, the tracks
+/// already downloaded are skipped. Only the last [`MAX_FINISHED_JOBS`] finished jobs are kept.
+#[derive(Clone)]
+pub struct JobQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    state: Mutex<State>,
+    queued: Notify,
+    changed: Notify,
+    events: broadcast::Sender<JobEvent>,
+}
+
+impl JobQueue {
+    /// Loads the queue saved in `path`, or starts an empty one.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut state = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice::<State>(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e.into()),
+        };
+        for job in state.jobs.iter_mut() {
+            if job.status == JobStatus::Running {
+                tracing::info!("Resuming job {}", job.id);
+                job.status = JobStatus::Queued;
+            }
+        }
+
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        Ok(JobQueue {
+            inner: Arc::new(Inner {
+                path,
+                state: Mutex::new(state),
+                queued: Notify::new(),
+                changed: Notify::new(),
+                events,
+            }),
+        })
+    }
+
+    pub fn jobs(&self) -> Vec<Job> {
+        self.inner.state.lock().unwrap().jobs.clone()
+    }
+
+    pub fn job(&self, id: u64) -> Option<Job> {
+        let state = self.inner.state.lock().unwrap();
+        state.jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<JobEvent> {
+        self.inner.events.subscribe()
+    }
+
+    pub fn enqueue(&self, uris: Vec<String>) -> Job {
+        let job = self.update(|state| {
+            state.next_id += 1;
+            let job = Job {
+                id: state.next_id,
+                uris,
+                status: JobStatus::Queued,
+                created_at: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs()),
+                total_tracks: None,
+                tracks: Vec::new(),
+                not_started: 0,
+                error: None,
+            };
+            state.jobs.push(job.clone());
+            job
+        });
+        tracing::info!("Queued job {}: {:?}", job.id, job.uris);
+        self.send(JobEvent::Status(job.clone()));
+        self.inner.queued.notify_one();
+        job
+    }
+
+    /// Cancels a job, aborting its tracks in progress if it is running. Returns `None` if there
+    /// is no such job.
+    pub fn cancel(&self, id: u64) -> Option<Job> {
+        let job = self.update(|state| {
+            if let Some(running) = state.running.as_mut().filter(|running| running.id == id) {
+                // The job is marked as cancelled once its tracks are aborted
+                running.cancelled = true;
+                running.shutdown.abort();
+            }
+            let job = state.jobs.iter_mut().find(|job| job.id == id)?;
+            if job.status == JobStatus::Queued {
+                job.status = JobStatus::Cancelled;
+            }
+            Some(job.clone())
+        });
+        if let Some(job) = job
+            .as_ref()
+            .filter(|job| job.status == JobStatus::Cancelled)
+        {
+            tracing::info!("Cancelled job {}", job.id);
+            self.send(JobEvent::Status(job.clone()));
+        }
+        job
+    }
+
+    /// Runs the queued jobs one after the other, until `stop` is cancelled. The job running at
+    /// that point is aborted and left queued, so it is picked up again on the next start.
+    ///
+    /// A job that fails is marked as such, it doesn't stop the jobs after it.
+    pub async fn run(
+        &self,
+        mut session: Session,
+        options: DownloadOptions,
+        stop: CancellationToken,
+    ) -> Result<()> {
+        loop {
+            let Some((job, shutdown)) = self.start_next() else {
+                tokio::select! {
+                    _ = self.inner.queued.notified() => continue,
+                    _ = stop.cancelled() => return Ok(()),
+                }
+            };
+
+            if session.is_invalid() {
+                tracing::info!("The session was closed, creating a new one");
+                match create_session().await {
+                    Ok(new_session) => session = new_session,
+                    Err(e) => {
+                        tracing::error!("Failed to create a session for job {}: {:?}", job.id, e);
+                        self.finish(job.id, Err(e));
+                        continue;
+                    }
+                }
+            }
+
+            let run = self.run_job(&job, &session, &options, shutdown.clone());
+            tokio::pin!(run);
+            let result = tokio::select! {
+                result = &mut run => result,
+                _ = stop.cancelled() => {
+                    shutdown.abort();
+                    // Lets the tracks in progress clean up after themselves
+                    let _ = run.await;
+                    self.requeue(job.id);
+                    return Ok(());
+                }
+            };
+            if let Err(e) = &result {
+                tracing::error!("Job {} failed: {:?}", job.id, e);
+            }
+            self.finish(job.id, result);
+        }
+    }
+
+    async fn run_job(
+        &self,
+        job: &Job,
+        session: &Session,
+        options: &DownloadOptions,
+        shutdown: Shutdown,
+    ) -> Result<DownloadReport> {
+        let groups = get_track_groups(job.uris.clone(), session, &options.retry).await?;
+        let total = groups.iter().map(|group| group.tracks.len()).sum();
+        self.update(|state| {
+            if let Some(job) = state.jobs.iter_mut().find(|j| j.id == job.id) {
+                job.total_tracks = Some(total);
+            }
+        });
+
+        let (finished, mut reports) = tokio::sync::mpsc::unbounded_channel();
+        let downloader = Downloader::new(session.clone())
+            .with_shutdown(shutdown)
+            .with_finished_tracks(finished)
+            .without_progress_bars();
+        let download = downloader.download(groups, options);
+        // The channel closes once the downloader is done with it
+        let track_finished = async {
+            while let Some(report) = reports.recv().await {
+                self.track_finished(job.id, report);
+            }
+        };
+        let (report, _) = tokio::join!(download, track_finished);
+        report
+    }
+
+    fn start_next(&self) -> Option<(Job, Shutdown)> {
+        let job = self.update(|state| {
+            let job = state
+                .jobs
+                .iter_mut()
+                .find(|job| job.status == JobStatus::Queued)?;
+            job.status = JobStatus::Running;
+            job.tracks.clear();
+            job.error = None;
+            let job = job.clone();
+            let shutdown = Shutdown::new();
+            state.running = Some(RunningJob {
+                id: job.id,
+                shutdown: shutdown.clone(),
+                cancelled: false,
+            });
+            Some((job, shutdown))
+        });
+        if let Some((job, _)) = &job {
+            tracing::info!("Starting job {}", job.id);
+            self.send(JobEvent::Status(job.clone()));
+        }
+        job
+    }
+
+    fn track_finished(&self, id: u64, report: TrackReport) {
+        self.update(|state| {
+            if let Some(job) = state.jobs.iter_mut().find(|job| job.id == id) {
+                job.tracks.push(report.clone());
+            }
+        });
+        self.send(JobEvent::Track { job: id, report });
+    }
+
+    fn finish(&self, id: u64, result: Result<DownloadReport>) {
+        let job = self.update(|state| {
+            let cancelled = state
+                .running
+                .take()
+                .is_some_and(|running| running.cancelled);
+            let job = state.jobs.iter_mut().find(|job| job.id == id)?;
+            match result {
+                Ok(report) => {
+                    job.tracks = report.tracks;
+                    job.not_started = report.not_started;
+                    job.status = if cancelled {
+                        JobStatus::Cancelled
+                    } else {
+                        JobStatus::Completed
+                    };
+                }
+                Err(e) => {
+                    job.error = Some(e.to_string());
+                    job.status = JobStatus::Failed;
+                }
+            }
+            Some(job.clone())
+        });
+        if let Some(job) = job {
+            tracing::info!("Job {} finished: {:?}", job.id, job.status);
+            self.send(JobEvent::Status(job));
+        }
+    }
+
+    fn requeue(&self, id: u64) {
+        self.update(|state| {
+            state.running = None;
+            if let Some(job) = state.jobs.iter_mut().find(|job| job.id == id) {
+                job.status = JobStatus::Queued;
+            }
+        })
+    }
+
+    /// Changes the state, which [`JobQueue::save_changes`] then saves.
+    fn update<T>(&self, f: impl FnOnce(&mut State) -> T) -> T {
+        let result = {
+            let mut state = self.inner.state.lock().unwrap();
+            let result = f(&mut state);
+            state.prune();
+            result
+        };
+        self.inner.changed.notify_one();
+        result
+    }
+
+    /// Saves the queue whenever it changes, until `stop` is cancelled. Changes made in the
+    /// meantime are saved together.
+    pub async fn save_changes(&self, stop: CancellationToken) -> Result<()> {
+        loop {
+            tokio::select! {
+                _ = self.inner.changed.notified() => {}
+                _ = stop.cancelled() => return Ok(()),
+            }
+            if let Err(e) = self.save().await {
+                tracing::warn!("Failed to save the job queue: {:?}", e);
+            }
+        }
+    }
+
+    /// Saves the queue as it is now. The file is replaced at once so it is never left half
+    /// written.
+    pub async fn save(&self) -> Result<()> {
+        let content = serde_json::to_vec_pretty(&*self.inner.state.lock().unwrap())?;
+        let path = self.inner.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let temporary = path.with_extension("json.tmp");
+            std::fs::write(&temporary, content)?;
+            std::fs::rename(&temporary, &path)
+        })
+        .await??;
+        Ok(())
+    }
+
+    fn send(&self, event: JobEvent) {
+        // Nobody listening is fine
+        let _ = self.inner.events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: u64, status: JobStatus) -> Job {
+        Job {
+            id,
+            uris: Vec::new(),
+            status,
+            created_at: 0,
+            total_tracks: None,
+            tracks: Vec::new(),
+            not_started: 0,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn prune_forgets_the_oldest_finished_jobs() {
+        let mut state = State::default();
+        state.jobs.push(job(0, JobStatus::Queued));
+        for id in 1..=MAX_FINISHED_JOBS as u64 + 2 {
+            state.jobs.push(job(id, JobStatus::Completed));
+        }
+
+        state.prune();
+
+        let ids: Vec<u64> = state.jobs.iter().map(|job| job.id).collect();
+        let expected: Vec<u64> = std::iter::once(0)
+            .chain(3..=MAX_FINISHED_JOBS as u64 + 2)
+            .collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn cancels_queued_jobs() {
+        let directory = tempfile::tempdir().unwrap();
+        let queue = JobQueue::load(directory.path().join("jobs.json")).unwrap();
+        let mut events = queue.subscribe();
+        let first = queue.enqueue(vec!["spotify:track:0000000000000000000001".to_string()]);
+        let second = queue.enqueue(vec!["spotify:track:0000000000000000000002".to_string()]);
+
+        let cancelled = queue.cancel(first.id).unwrap();
+
+        assert_eq!(cancelled.status, JobStatus::Cancelled);
+        assert_eq!(queue.job(first.id).unwrap().status, JobStatus::Cancelled);
+        assert_eq!(queue.job(second.id).unwrap().status, JobStatus::Queued);
+        assert!(queue.cancel(3).is_none());
+        let statuses: Vec<(u64, JobStatus)> = std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                JobEvent::Status(job) => Some((job.id, job.status)),
+                JobEvent::Track { .. } => None,
+            })
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (first.id, JobStatus::Queued),
+                (second.id, JobStatus::Queued),
+                (first.id, JobStatus::Cancelled),
+            ]
+        );
+
+        // The next job to run is the one left queued
+        let (next, _) = queue.start_next().unwrap();
+        assert_eq!(next.id, second.id);
+    }
+
+    #[tokio::test]
+    async fn load_queues_running_jobs_again() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("jobs.json");
+        let state = State {
+            jobs: vec![
+                job(1, JobStatus::Completed),
+                job(2, JobStatus::Running),
+                job(3, JobStatus::Queued),
+            ],
+            next_id: 3,
+            running: None,
+        };
+        std::fs::write(&path, serde_json::to_vec(&state).unwrap()).unwrap();
+
+        let queue = JobQueue::load(path.clone()).unwrap();
+
+        let statuses: Vec<JobStatus> = queue.jobs().iter().map(|job| job.status).collect();
+        assert_eq!(
+            statuses,
+            vec![JobStatus::Completed, JobStatus::Queued, JobStatus::Queued]
+        );
+        assert_eq!(queue.enqueue(Vec::new()).id, 4);
+
+        // Saved and loaded back as it is
+        queue.save().await.unwrap();
+        let loaded = JobQueue::load(path).unwrap();
+        let ids: Vec<u64> = loaded.jobs().iter().map(|job| job.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,51 @@
+//! The `serve` daemon, which keeps a single session alive and downloads the jobs queued
+//! through a local HTTP API.
+//!
+//! - `GET /jobs` lists the jobs, `GET /jobs/{id}` returns one of them.
+//! - `POST /jobs` queues a job, with a body like `{"uris": ["spotify:album:..."]}`.
+//! - `DELETE /jobs/{id}` cancels a job, aborting its tracks in progress.
+//! - `GET /jobs/{id}/events` follows the progress of a job as server-sent events.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use librespot::core::session::Session;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+use crate::download::DownloadOptions;
+use crate::utils::get_dot_path;
+
+mod http;
+pub mod jobs;
+
+pub use jobs::Job;
+pub use jobs::JobQueue;
+pub use jobs::JobStatus;
+
+/// Where the job queue is kept unless told otherwise.
+pub fn default_jobs_path() -> Result<PathBuf> {
+    Ok(get_dot_path()?.join("jobs.json"))
+}
+
+/// Serves the API on `address` and runs the queued jobs with `options`, until `stop` is
+/// cancelled.
+pub async fn serve(
+    address: SocketAddr,
+    queue: JobQueue,
+    session: Session,
+    options: DownloadOptions,
+    stop: CancellationToken,
+) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("Listening on {}", address);
+    println!("Listening on http://{}", address);
+
+    let server = http::serve(listener, queue.clone(), stop.clone());
+    let worker = queue.run(session, options, stop.clone());
+    let saver = queue.save_changes(stop);
+    tokio::try_join!(server, worker, saver)?;
+    // The job that was running is left queued once the worker stops
+    queue.save().await
+}
@@ -30,9 +30,6 @@ pub async fn create_session() -> Result<Session> {
 }
 
 pub fn load_credentials() -> Result<Credentials> {
-    let token = match get_access_token(SPOTIFY_CLIENT_ID, SPOTIFY_REDIRECT_URI, vec!["streaming"]) {
-        Ok(token) => token,
-        Err(e) => return Err(e.into()),
-    };
+    let token = get_access_token(SPOTIFY_CLIENT_ID, SPOTIFY_REDIRECT_URI, vec!["streaming"])?;
     Ok(Credentials::with_access_token(token.access_token))
 }
@@ -0,0 +1,69 @@
+use tokio_util::sync::CancellationToken;
+
+/// Returned when a task is interrupted by an abort.
+#[derive(Debug, thiserror::Error)]
+#[error("Cancelled")]
+pub struct Cancelled;
+
+/// Coordinates stopping a run early.
+///
+/// Stopping lets the tracks in progress finish but doesn't start new ones, aborting also
+/// interrupts the tracks in progress.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    stop: CancellationToken,
+    abort: CancellationToken,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+
+    pub fn abort(&self) {
+        self.stop.cancel();
+        self.abort.cancel();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop.is_cancelled()
+    }
+
+    /// Resolves once the run is stopped.
+    pub async fn stopped(&self) {
+        self.stop.cancelled().await
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.abort.is_cancelled()
+    }
+
+    /// A token cancelled on abort, for the tasks that have to drop what they are doing.
+    pub fn abort_token(&self) -> CancellationToken {
+        self.abort.clone()
+    }
+
+    /// Stops on the first Ctrl-C and aborts on the second one.
+    pub fn listen_for_ctrl_c(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            tracing::warn!("Interrupted, finishing the tracks in progress");
+            eprintln!("Finishing the tracks in progress, press Ctrl-C again to abort");
+            shutdown.stop();
+
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            tracing::warn!("Interrupted again, aborting");
+            eprintln!("Aborting");
+            shutdown.abort();
+        });
+    }
+}
@@ -0,0 +1,186 @@
+//! Making sure a download doesn't run out of disk space halfway.
+//!
+//! The size of the files is estimated from the duration of the tracks: close for MP3, which has
+//! a constant bitrate, and on the large side for FLAC, whose size depends on the music.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use anyhow::Result;
+use indicatif::HumanBytes;
+use sysinfo::Disks;
+
+use crate::encoder::Format;
+
+/// Kept free on top of the estimated size of the files, so the disk is never filled to the brim.
+pub const RESERVED_SPACE: u64 = 256 * 1024 * 1024;
+/// The cover and tags of every file, which don't depend on its duration.
+const FILE_OVERHEAD: u64 = 512 * 1024;
+/// How often the free space is checked again while waiting for some.
+pub const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What to do when the destination looks too small for a download.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum SpaceCheck {
+    /// Estimate the whole download before starting and warn if it doesn't fit, then wait for
+    /// space before every track.
+    #[default]
+    Warn,
+    /// Estimate the whole download before starting and don't start at all if it doesn't fit,
+    /// then wait for space before every track.
+    Abort,
+    Off,
+}
+
+impl FromStr for SpaceCheck {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "warn" => Ok(SpaceCheck::Warn),
+            "abort" => Ok(SpaceCheck::Abort),
+            "off" => Ok(SpaceCheck::Off),
+            _ => Err(anyhow::anyhow!(
+                "Unsupported space check, use warn, abort or off"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "Not enough space in {}: the download needs about {}, only {} are free",
+    .mount_point.display(),
+    HumanBytes(*.needed),
+    HumanBytes(*.available)
+)]
+pub struct NotEnoughSpace {
+    pub mount_point: PathBuf,
+    pub needed: u64,
+    pub available: u64,
+}
+
+/// The estimated size of a file holding `duration_ms` of audio.
+pub fn estimated_size(format: Format, duration_ms: u64) -> u64 {
+    let bytes_per_second = match format {
+        // 24 bit stereo, which usually compresses to about two thirds
+        Format::Flac => 44100 * 2 * 3 * 2 / 3,
+        #[cfg(feature = "mp3")]
+        Format::Mp3 => 320 * 1000 / 8,
+    };
+    bytes_per_second * duration_ms / 1000 + FILE_OVERHEAD
+}
+
+/// A filesystem files are written to.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Filesystem {
+    pub mount_point: PathBuf,
+    /// In bytes.
+    pub available: u64,
+}
+
+impl Filesystem {
+    /// The filesystem every one of `paths` is on, going by the nearest directory that exists.
+    /// `None` for the ones that can't be told, they are left unchecked.
+    pub fn of(paths: &[&Path]) -> Vec<Option<Filesystem>> {
+        let disks = Disks::new_with_refreshed_list();
+        paths
+            .iter()
+            .map(|path| {
+                let path = nearest_existing(path)?;
+                let disk = disks
+                    .list()
+                    .iter()
+                    .filter(|disk| path.starts_with(disk.mount_point()))
+                    .max_by_key(|disk| disk.mount_point().as_os_str().len())?;
+                Some(Filesystem {
+                    mount_point: disk.mount_point().to_path_buf(),
+                    available: disk.available_space(),
+                })
+            })
+            .collect()
+    }
+}
+
+fn nearest_existing(path: &Path) -> Option<PathBuf> {
+    let absolute = std::path::absolute(path).ok()?;
+    let existing = absolute.ancestors().find(|ancestor| ancestor.exists())?;
+    std::fs::canonicalize(existing).ok()
+}
+
+/// The filesystems that don't have room for what is going to be written to them, given the
+/// bytes needed in each directory and `in_flight` bytes already promised on each filesystem.
+pub fn missing_space(needed: &HashMap<PathBuf, u64>, in_flight: u64) -> Vec<NotEnoughSpace> {
+    let directories: Vec<&Path> = needed.keys().map(PathBuf::as_path).collect();
+    let mut by_filesystem: HashMap<PathBuf, (u64, u64)> = HashMap::new();
+    for (directory, filesystem) in directories.iter().zip(Filesystem::of(&directories)) {
+        let Some(filesystem) = filesystem else {
+            tracing::debug!("Can't tell the free space of {}", directory.display());
+            continue;
+        };
+        let entry = by_filesystem
+            .entry(filesystem.mount_point)
+            .or_insert((in_flight, filesystem.available));
+        entry.0 += needed[*directory];
+    }
+
+    let mut missing: Vec<NotEnoughSpace> = by_filesystem
+        .into_iter()
+        .filter(|(_, (needed, available))| needed + RESERVED_SPACE > *available)
+        .map(|(mount_point, (needed, available))| NotEnoughSpace {
+            mount_point,
+            needed,
+            available,
+        })
+        .collect();
+    missing.sort_by(|a, b| a.mount_point.cmp(&b.mount_point));
+    missing
+}
+
+/// Keeps track of the files being downloaded, which take up space only once they are written.
+#[derive(Debug, Default)]
+pub struct SpaceMonitor {
+    in_flight: AtomicU64,
+}
+
+impl SpaceMonitor {
+    /// Claims the space for files about to be downloaded if there's room for them on top of the
+    /// ones in progress, given the bytes needed in each directory.
+    pub fn try_reserve(
+        &self,
+        needed: &HashMap<PathBuf, u64>,
+    ) -> Result<Reservation<'_>, NotEnoughSpace> {
+        // The files in progress may be anywhere, so they count against every filesystem
+        let in_flight = self.in_flight.load(Ordering::SeqCst);
+        if let Some(missing) = missing_space(needed, in_flight).into_iter().next() {
+            return Err(missing);
+        }
+
+        let bytes = needed.values().sum();
+        self.in_flight.fetch_add(bytes, Ordering::SeqCst);
+        Ok(Reservation {
+            monitor: self,
+            bytes,
+        })
+    }
+}
+
+/// Space claimed for files being downloaded, given back once they are written or given up on.
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    monitor: &'a SpaceMonitor,
+    bytes: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.monitor
+            .in_flight
+            .fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
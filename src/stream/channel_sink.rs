@@ -1,51 +1,110 @@
+use std::sync::{Arc, Mutex};
+
 use librespot::playback::audio_backend::Sink;
 use librespot::playback::audio_backend::SinkError;
 use librespot::playback::convert::Converter;
 use librespot::playback::decoder::AudioPacket;
+use tokio::sync::mpsc::Sender;
 
+use crate::stream::CHANNEL_CAPACITY;
+use crate::stream::SourceQuality;
+use crate::stream::progress::SAMPLES_PER_SECOND;
+use crate::throttle::Throttle;
 use crate::track::TrackMetadata;
 
 pub enum SinkEvent {
     Write {
-        bytes: usize,
-        total: usize,
+        position: u64,
+        total: u64,
         content: Vec<i32>,
     },
     Finished,
 }
-pub type SinkEventChannel = tokio::sync::mpsc::UnboundedReceiver<SinkEvent>;
+pub type SinkEventChannel = tokio::sync::mpsc::Receiver<SinkEvent>;
+
+/// Where the sink sends the audio of the track being played.
+struct Target {
+    sender: Sender<SinkEvent>,
+    samples_total: u64,
+    samples_sent: u64,
+    source_bytes_per_second: u64,
+    throttle: Throttle,
+}
 
+/// A sink that sends the audio it receives over a channel.
+///
+/// The player owns its sink for as long as it lives, so the channel is swapped through a
+/// [`SinkHandle`] for every track. Audio received while no track is attached is dropped.
 pub struct ChannelSink {
-    sender: tokio::sync::mpsc::UnboundedSender<SinkEvent>,
-    bytes_total: usize,
-    bytes_sent: usize,
+    target: Arc<Mutex<Option<Target>>>,
 }
 
-impl ChannelSink {
-    pub fn new(track: TrackMetadata) -> (Self, SinkEventChannel) {
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+/// Points a [`ChannelSink`] at a track, from outside the player.
+#[derive(Clone)]
+pub struct SinkHandle {
+    target: Arc<Mutex<Option<Target>>>,
+}
 
+impl ChannelSink {
+    pub fn new() -> (Self, SinkHandle) {
+        let target = Arc::new(Mutex::new(None));
         (
             ChannelSink {
-                sender: tx,
-                bytes_sent: 0,
-                bytes_total: Self::convert_track_duration_to_size(&track),
+                target: target.clone(),
             },
-            rx,
+            SinkHandle { target },
         )
     }
 
-    fn convert_track_duration_to_size(metadata: &TrackMetadata) -> usize {
-        let duration = metadata.duration / 1000;
-        let sample_rate = 44100;
-        let channels = 2;
-        let bits_per_sample = 16;
-        let bytes_per_sample = bits_per_sample / 8;
-        (duration as usize) * sample_rate * channels * bytes_per_sample * 2
+    /// Sends an event, waiting for room in the channel. This runs on the player thread, so
+    /// blocking it is what holds the player back when the consumer falls behind.
+    ///
+    /// A closed channel isn't an error: the stream was dropped and the player is about to be
+    /// stopped, while the player exits the process when its sink fails.
+    fn send(sender: Sender<SinkEvent>, event: SinkEvent) {
+        // The player drives itself from a runtime of its own, which has to be left to block
+        let sent = tokio::task::block_in_place(|| sender.blocking_send(event));
+        if sent.is_err() {
+            tracing::debug!("The stream was closed, dropping the audio");
+        }
+    }
+}
+
+impl SinkHandle {
+    /// Sends the audio played from now on to a new channel, paced by the bandwidth limit of
+    /// `throttle` as if the audio was fetched at the bitrate of `source`.
+    pub fn attach(
+        &self,
+        track: &TrackMetadata,
+        source: Option<SourceQuality>,
+        throttle: Throttle,
+    ) -> SinkEventChannel {
+        let (tx, rx) = tokio::sync::mpsc::channel(CHANNEL_CAPACITY);
+        *self.target.lock().unwrap() = Some(Target {
+            sender: tx,
+            samples_total: track.total_samples(),
+            samples_sent: 0,
+            source_bytes_per_second: source
+                .map_or(320 * 1000 / 8, |source| source.bytes_per_second() as u64),
+            throttle,
+        });
+        rx
+    }
+
+    /// Stops sending audio, which also closes the channel.
+    pub fn detach(&self) {
+        self.target.lock().unwrap().take();
     }
+}
 
-    pub fn get_approximate_size(&self) -> usize {
-        self.bytes_total
+impl Target {
+    /// The size of the samples in the source file. The player fetches the file on its own, so
+    /// this goes by the bitrate of the source rather than by the bytes actually fetched.
+    fn source_bytes(&self, samples: usize) -> u32 {
+        let before = self.samples_sent * self.source_bytes_per_second / SAMPLES_PER_SECOND;
+        let after = (self.samples_sent + samples as u64) * self.source_bytes_per_second
+            / SAMPLES_PER_SECOND;
+        (after - before) as u32
     }
 }
 
@@ -57,9 +116,15 @@ impl Sink for ChannelSink {
     fn stop(&mut self) -> Result<(), SinkError> {
         tracing::info!("Finished sending song");
 
-        self.sender
-            .send(SinkEvent::Finished)
-            .map_err(|_| SinkError::OnWrite("Failed to send finished event".to_string()))?;
+        let sender = self
+            .target
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|t| t.sender.clone());
+        if let Some(sender) = sender {
+            Self::send(sender, SinkEvent::Finished);
+        }
         Ok(())
     }
 
@@ -69,16 +134,36 @@ impl Sink for ChannelSink {
                 .samples()
                 .map_err(|_| SinkError::OnWrite("Failed to get samples".to_string()))?,
         );
-        self.bytes_sent += data.len() * std::mem::size_of::<i32>();
 
-        self.sender
-            .send(SinkEvent::Write {
-                bytes: self.bytes_sent,
-                total: self.bytes_total,
-                content: data,
-            })
-            .map_err(|_| SinkError::OnWrite("Failed to send event".to_string()))?;
+        // The lock isn't held while waiting for the throttle or the channel, so the track can
+        // be detached in the meantime
+        let (sender, position, total, throttle, bytes) = {
+            let mut target = self.target.lock().unwrap();
+            let Some(target) = target.as_mut() else {
+                return Ok(());
+            };
+            let bytes = target.source_bytes(data.len());
+            target.samples_sent += data.len() as u64;
+            (
+                target.sender.clone(),
+                target.samples_sent,
+                target.samples_total,
+                target.throttle.clone(),
+                bytes,
+            )
+        };
+        // Holds the player back, and with it the fetching of the rest of the file, so the
+        // track doesn't go faster than the bandwidth limit
+        throttle.consume_bandwidth_blocking(bytes);
 
+        Self::send(
+            sender,
+            SinkEvent::Write {
+                position,
+                total,
+                content: data,
+            },
+        );
         Ok(())
     }
 }
@@ -0,0 +1,298 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Duration;
+
+use anyhow::Result;
+use librespot::audio::{AudioDecrypt, AudioFile, StreamLoaderController};
+use librespot::core::Session;
+use librespot::metadata::audio::{AudioFiles, AudioItem};
+use librespot::playback::config::PlayerConfig;
+use librespot::playback::convert::Converter;
+use librespot::playback::decoder::{AudioDecoder, SymphoniaDecoder};
+use symphonia::core::io::MediaSource;
+use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
+
+use crate::retry::RetryPolicy;
+use crate::stream::progress::samples_for_duration_ms;
+use crate::stream::{
+    CHANNEL_CAPACITY, Quality, SourceQuality, StreamError, StreamEvent, StreamEventChannel,
+    Timeouts,
+};
+use crate::throttle::Throttle;
+use crate::track::Track;
+
+/// Spotify prepends a custom header to its Ogg files, the Vorbis stream starts after it.
+const SPOTIFY_OGG_HEADER_END: u64 = 0xa7;
+
+/// An audio file ready to be decoded.
+struct OpenedFile {
+    file: AudioDecrypt<AudioFile>,
+    controller: StreamLoaderController,
+    source: SourceQuality,
+    length: u64,
+    duration_ms: u32,
+}
+
+/// Streams a track by fetching, decrypting and decoding its audio file ourselves.
+///
+/// Unlike [`Stream`](crate::stream::Stream) there is no `Player` in between, so it isn't held
+/// back by the playback pipeline and runs as fast as the file can be downloaded.
+pub struct DirectStream {
+    session: Session,
+    retry: RetryPolicy,
+    throttle: Throttle,
+    quality: Quality,
+    timeouts: Timeouts,
+    cancel: CancellationToken,
+}
+
+impl DirectStream {
+    /// Cancelling `cancel` stops fetching and closes the stream without a `Finished` event.
+    pub fn new(
+        session: Session,
+        retry: RetryPolicy,
+        throttle: Throttle,
+        quality: Quality,
+        timeouts: Timeouts,
+        cancel: CancellationToken,
+    ) -> Self {
+        DirectStream {
+            session,
+            retry,
+            throttle,
+            quality,
+            timeouts,
+            cancel,
+        }
+    }
+
+    pub async fn stream(&self, track: Track) -> Result<StreamEventChannel> {
+        let (tx, rx) = tokio::sync::mpsc::channel(CHANNEL_CAPACITY);
+        let session = self.session.clone();
+        let retry = self.retry.clone();
+        let throttle = self.throttle.clone();
+        let quality = self.quality;
+        let load_timeout = self.timeouts.load;
+        let cancel = self.cancel.clone();
+
+        tokio::spawn(async move {
+            let opened = tokio::select! {
+                opened = Self::open(&session, &retry, &throttle, quality, load_timeout, &track) => opened,
+                _ = cancel.cancelled() => Err(StreamError::Cancelled),
+            };
+            let opened = match opened {
+                Ok(opened) => opened,
+                Err(StreamError::Cancelled) => {
+                    tracing::info!("Stream of {:?} was aborted while opening it", track.id);
+                    return;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to open track: {:?}, error: {:?}", track.id, e);
+                    let _ = tx.send(StreamEvent::Error(e)).await;
+                    return;
+                }
+            };
+
+            tracing::info!("Fetching track: {:?} ({})", track.id, opened.source);
+            if tx.send(StreamEvent::Source(opened.source)).await.is_err() {
+                opened.controller.close();
+                return;
+            }
+            let events = tx.clone();
+            let decoded = tokio::task::spawn_blocking(move || {
+                Self::decode(opened, throttle, &events, &cancel)
+            })
+            .await
+            .unwrap_or(Err(StreamError::Unknown));
+
+            let event = match decoded {
+                Ok(()) => StreamEvent::Finished,
+                Err(StreamError::Cancelled) => {
+                    tracing::info!("Stream of {:?} was cancelled", track.id);
+                    return;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to decode track: {:?}, error: {:?}", track.id, e);
+                    StreamEvent::Error(e)
+                }
+            };
+            let _ = tx.send(event).await;
+        });
+
+        Ok(rx)
+    }
+
+    async fn open(
+        session: &Session,
+        retry: &RetryPolicy,
+        throttle: &Throttle,
+        quality: Quality,
+        load_timeout: Duration,
+        track: &Track,
+    ) -> Result<OpenedFile, StreamError> {
+        let load_error = |e: librespot::core::Error| StreamError::LoadError(e.to_string());
+
+        let item = retry
+            .retry("get audio files", || {
+                Self::with_timeout(load_timeout, AudioItem::get_file(session, track.id))
+            })
+            .await
+            .map_err(load_error)?;
+        let (source, file_id) = quality
+            .select(&item.files)
+            .ok_or_else(|| StreamError::Unavailable(format!("{:?}", track.id)))?;
+
+        let file = retry
+            .retry("open audio file", || {
+                Self::with_timeout(
+                    load_timeout,
+                    AudioFile::open(session, file_id, source.bytes_per_second()),
+                )
+            })
+            .await
+            .map_err(load_error)?;
+
+        // Ask for the whole file straight away, instead of letting the loader follow the decoder.
+        // With a bandwidth limit we want the opposite, so the limit applies to the download.
+        let controller = file.get_stream_loader_controller().map_err(load_error)?;
+        if !throttle.limits_bandwidth() {
+            controller
+                .fetch_next_and_wait(controller.len(), 0)
+                .map_err(load_error)?;
+        }
+
+        // Not all audio files are encrypted, the decoder will fail if this one was
+        let key = match retry
+            .retry("get audio key", || {
+                Self::with_timeout(load_timeout, session.audio_key().request(track.id, file_id))
+            })
+            .await
+        {
+            Ok(key) => Some(key),
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to get audio key, continuing without decryption: {}",
+                    e
+                );
+                None
+            }
+        };
+
+        Ok(OpenedFile {
+            file: AudioDecrypt::new(key, file),
+            source,
+            length: controller.len() as u64,
+            controller,
+            duration_ms: item.duration_ms,
+        })
+    }
+
+    /// Fails a request to Spotify that takes too long, the error is retried like a network one.
+    async fn with_timeout<T>(
+        timeout: Duration,
+        request: impl Future<Output = Result<T, librespot::core::Error>>,
+    ) -> Result<T, librespot::core::Error> {
+        tokio::time::timeout(timeout, request)
+            .await
+            .unwrap_or_else(|e| Err(librespot::core::Error::deadline_exceeded(e)))
+    }
+
+    fn decode(
+        opened: OpenedFile,
+        throttle: Throttle,
+        tx: &Sender<StreamEvent>,
+        cancel: &CancellationToken,
+    ) -> Result<(), StreamError> {
+        let decode_error = |e: &dyn std::fmt::Display| StreamError::DecodeError(e.to_string());
+
+        let controller = opened.controller;
+        let format = opened.source.format;
+        let offset = if AudioFiles::is_ogg_vorbis(format) {
+            SPOTIFY_OGG_HEADER_END
+        } else {
+            0
+        };
+        let source = AudioSource::new(opened.file, offset, opened.length, throttle)
+            .map_err(|e| decode_error(&e))?;
+        let total = samples_for_duration_ms(opened.duration_ms as u64);
+        let mut position = 0;
+
+        let mut decoder = SymphoniaDecoder::new(source, format).map_err(|e| decode_error(&e))?;
+        let mut converter = Converter::new(PlayerConfig::default().ditherer);
+
+        while let Some((_, packet)) = decoder.next_packet().map_err(|e| decode_error(&e))? {
+            let samples = packet.samples().map_err(|e| decode_error(&e))?;
+            let content = converter.f64_to_s32(samples);
+            position += content.len() as u64;
+            let event = StreamEvent::Write {
+                position,
+                total,
+                content,
+            };
+            // Waits for room in the channel, which holds decoding back if the consumer is slower
+            if cancel.is_cancelled() || tx.blocking_send(event).is_err() {
+                controller.close();
+                return Err(StreamError::Cancelled);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The decrypted audio file, starting after Spotify's header.
+struct AudioSource<T: Read + Seek> {
+    file: T,
+    offset: u64,
+    length: u64,
+    throttle: Throttle,
+}
+
+impl<T: Read + Seek> AudioSource<T> {
+    fn new(mut file: T, offset: u64, length: u64, throttle: Throttle) -> std::io::Result<Self> {
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(AudioSource {
+            file,
+            offset,
+            length,
+            throttle,
+        })
+    }
+}
+
+impl<T: Read + Seek> Read for AudioSource<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.file.read(buf)?;
+        // The loader fetches the file as it is read, a few seconds ahead at most, so charging
+        // the bytes read is what holds the download to the bandwidth limit
+        self.throttle.consume_bandwidth_blocking(read as u32);
+        Ok(read)
+    }
+}
+
+impl<T: Read + Seek> Seek for AudioSource<T> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(position) => SeekFrom::Start(position + self.offset),
+            pos => pos,
+        };
+        let position = self.file.seek(pos)?;
+        if position < self.offset {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seeking before the start of the audio stream",
+            ));
+        }
+        Ok(position - self.offset)
+    }
+}
+
+impl<T: Read + Seek + Send + Sync> MediaSource for AudioSource<T> {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.length - self.offset)
+    }
+}
@@ -1,15 +1,31 @@
 pub mod channel_sink;
+pub mod direct;
+pub mod pool;
+pub mod progress;
+pub mod quality;
+#[allow(clippy::module_inception)]
 pub mod stream;
+pub mod timeouts;
+
+/// How many events can be waiting to be consumed before the producer is held back.
+pub const CHANNEL_CAPACITY: usize = 64;
 
 // Re-export the Stream type for easier access
+pub use direct::DirectStream;
+pub use quality::{Quality, SourceQuality};
 pub use stream::Stream;
+pub use timeouts::Timeouts;
 
 pub enum StreamEvent {
+    /// Decoded audio, with the progress of the track in samples (see [`progress`]).
     Write {
-        bytes: usize,
-        total: usize,
+        position: u64,
+        total: u64,
         content: Vec<i32>,
     },
+    /// The audio file the track is being streamed from, sent once it is loaded and before any
+    /// audio.
+    Source(SourceQuality),
     Finished,
     Retry{
         attempt: usize,
@@ -23,8 +39,20 @@ pub enum StreamError {
     #[error("Failed to load track: {0}")]
     LoadError(String),
 
+    #[error("Track is unavailable: {0}")]
+    Unavailable(String),
+
+    #[error("Failed to decode track: {0}")]
+    DecodeError(String),
+
+    #[error("Timed out: {0}")]
+    Timeout(String),
+
+    #[error("Stream was cancelled")]
+    Cancelled,
+
     #[error("Unknown error occurred")]
     Unknown,
 }
 
-pub type StreamEventChannel = tokio::sync::mpsc::UnboundedReceiver<StreamEvent>;
\ No newline at end of file
+pub type StreamEventChannel = tokio::sync::mpsc::Receiver<StreamEvent>;
\ No newline at end of file
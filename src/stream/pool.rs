@@ -0,0 +1,89 @@
+use std::sync::{Arc, Mutex};
+
+use librespot::core::Session;
+use librespot::playback::config::{Bitrate, PlayerConfig};
+use librespot::playback::mixer::NoOpVolume;
+use librespot::playback::player::Player;
+
+use crate::stream::channel_sink::{ChannelSink, SinkHandle};
+
+/// A player and the sink it plays into, which can be pointed at a new track every time.
+pub struct PooledPlayer {
+    pub player: Arc<Player>,
+    pub sink: SinkHandle,
+    bitrate: Bitrate,
+}
+
+/// Long-lived players shared by the streams of a download run.
+///
+/// Creating a player spins up its own thread and runtime, so instead of doing it for every track
+/// the streams take an idle player from here and give it back once the track is done. There are
+/// never more players than tracks streamed at the same time, i.e. `--parallel`.
+#[derive(Clone)]
+pub struct PlayerPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    session: Session,
+    idle: Mutex<Vec<PooledPlayer>>,
+}
+
+impl PlayerPool {
+    pub fn new(session: Session) -> Self {
+        PlayerPool {
+            inner: Arc::new(Inner {
+                session,
+                idle: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.inner.session
+    }
+
+    /// Takes an idle player playing at `bitrate`, or creates one if there is none.
+    pub fn acquire(&self, bitrate: Bitrate) -> PooledPlayer {
+        let mut idle = self.inner.idle.lock().unwrap();
+        if let Some(index) = idle
+            .iter()
+            .position(|pooled| pooled.bitrate == bitrate && !pooled.player.is_invalid())
+        {
+            return idle.swap_remove(index);
+        }
+        drop(idle);
+
+        tracing::debug!("Creating a new player for {:?}", bitrate);
+        let config = PlayerConfig {
+            bitrate,
+            ..Default::default()
+        };
+        let (sink, handle) = ChannelSink::new();
+        let player = Player::new(
+            config,
+            self.inner.session.clone(),
+            Box::new(NoOpVolume),
+            move || Box::new(sink),
+        );
+        PooledPlayer {
+            player,
+            sink: handle,
+            bitrate,
+        }
+    }
+
+    /// Gives back a player that finished its track cleanly, so it can be used for the next one.
+    pub fn release(&self, pooled: PooledPlayer) {
+        pooled.sink.detach();
+        self.inner.idle.lock().unwrap().push(pooled);
+    }
+
+    /// Gets rid of a player that may be stuck, instead of handing it to another track.
+    pub fn discard(pooled: PooledPlayer) {
+        pooled.sink.detach();
+        pooled.player.stop();
+        // Dropping the last reference joins the player thread, which may take a while
+        tokio::task::spawn_blocking(move || drop(pooled));
+    }
+}
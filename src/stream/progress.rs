@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+use librespot::playback::{NUM_CHANNELS, SAMPLE_RATE};
+
+/// Interleaved samples in a second of decoded audio, counting every channel.
+///
+/// Streaming progress is measured in these samples, both by the streams and the progress bars.
+pub const SAMPLES_PER_SECOND: u64 = SAMPLE_RATE as u64 * NUM_CHANNELS as u64;
+
+pub fn samples_for_duration_ms(duration_ms: u64) -> u64 {
+    duration_ms * SAMPLES_PER_SECOND / 1000
+}
+
+pub fn duration_for_samples(samples: u64) -> Duration {
+    Duration::from_millis(samples * 1000 / SAMPLES_PER_SECOND)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_every_channel() {
+        assert_eq!(samples_for_duration_ms(1000), 88200);
+        assert_eq!(samples_for_duration_ms(0), 0);
+        // 3:25.507, rounded down to a whole sample
+        assert_eq!(samples_for_duration_ms(205_507), 18_125_717);
+    }
+
+    #[test]
+    fn converts_back_to_a_duration() {
+        assert_eq!(duration_for_samples(88200), Duration::from_secs(1));
+        assert_eq!(
+            duration_for_samples(samples_for_duration_ms(205_507)),
+            Duration::from_millis(205_506)
+        );
+    }
+}
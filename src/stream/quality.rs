@@ -0,0 +1,138 @@
+use std::str::FromStr;
+
+use librespot::core::FileId;
+use librespot::metadata::audio::{AudioFileFormat, AudioFiles};
+use librespot::playback::config::Bitrate;
+
+/// The quality of the audio files to download from Spotify.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum Quality {
+    /// 96 kbps
+    Low,
+    /// 160 kbps
+    Normal,
+    /// 320 kbps
+    #[default]
+    High,
+}
+
+impl FromStr for Quality {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "low" | "96" => Ok(Quality::Low),
+            "normal" | "160" => Ok(Quality::Normal),
+            "high" | "320" => Ok(Quality::High),
+            _ => Err(anyhow::anyhow!(
+                "Unsupported quality, expected low, normal or high"
+            )),
+        }
+    }
+}
+
+impl Quality {
+    pub fn bitrate(&self) -> Bitrate {
+        match self {
+            Quality::Low => Bitrate::Bitrate96,
+            Quality::Normal => Bitrate::Bitrate160,
+            Quality::High => Bitrate::Bitrate320,
+        }
+    }
+
+    pub fn kbps(&self) -> u32 {
+        match self {
+            Quality::Low => 96,
+            Quality::Normal => 160,
+            Quality::High => 320,
+        }
+    }
+
+    /// The audio files to look for, in order of preference. It is the same fallback chain the
+    /// player uses, so we know which file it is going to pick.
+    pub fn formats(&self) -> [AudioFileFormat; 7] {
+        match self {
+            Quality::Low => [
+                AudioFileFormat::OGG_VORBIS_96,
+                AudioFileFormat::MP3_96,
+                AudioFileFormat::OGG_VORBIS_160,
+                AudioFileFormat::MP3_160,
+                AudioFileFormat::MP3_256,
+                AudioFileFormat::OGG_VORBIS_320,
+                AudioFileFormat::MP3_320,
+            ],
+            Quality::Normal => [
+                AudioFileFormat::OGG_VORBIS_160,
+                AudioFileFormat::MP3_160,
+                AudioFileFormat::OGG_VORBIS_96,
+                AudioFileFormat::MP3_96,
+                AudioFileFormat::MP3_256,
+                AudioFileFormat::OGG_VORBIS_320,
+                AudioFileFormat::MP3_320,
+            ],
+            Quality::High => [
+                AudioFileFormat::OGG_VORBIS_320,
+                AudioFileFormat::MP3_320,
+                AudioFileFormat::MP3_256,
+                AudioFileFormat::OGG_VORBIS_160,
+                AudioFileFormat::MP3_160,
+                AudioFileFormat::OGG_VORBIS_96,
+                AudioFileFormat::MP3_96,
+            ],
+        }
+    }
+
+    /// Picks the audio file closest to this quality.
+    pub fn select(&self, files: &AudioFiles) -> Option<(SourceQuality, FileId)> {
+        let selected = self.formats().into_iter().find_map(|format| {
+            files
+                .get(&format)
+                .map(|file_id| (SourceQuality { format }, *file_id))
+        });
+        if let Some((source, _)) = &selected
+            && source.kbps() != self.kbps()
+        {
+            tracing::info!(
+                "No {} kbps audio file available, falling back to {}",
+                self.kbps(),
+                source
+            );
+        }
+        selected
+    }
+}
+
+/// The audio file a track was actually downloaded from.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SourceQuality {
+    pub format: AudioFileFormat,
+}
+
+impl SourceQuality {
+    pub fn kbps(&self) -> u32 {
+        match self.format {
+            AudioFileFormat::OGG_VORBIS_320 | AudioFileFormat::MP3_320 => 320,
+            AudioFileFormat::MP3_256 => 256,
+            AudioFileFormat::OGG_VORBIS_160 | AudioFileFormat::MP3_160 => 160,
+            _ => 96,
+        }
+    }
+
+    pub fn codec(&self) -> &'static str {
+        if AudioFiles::is_ogg_vorbis(self.format) {
+            "vorbis"
+        } else {
+            "mp3"
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> usize {
+        self.kbps() as usize * 1000 / 8
+    }
+}
+
+impl std::fmt::Display for SourceQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} kbps", self.codec(), self.kbps())
+    }
+}
@@ -1,129 +1,205 @@
 use std::sync::Arc;
-use std::time::Duration;
 
 use anyhow::Result;
-use librespot::core::Session;
-use librespot::playback::config::{Bitrate, PlayerConfig};
-use librespot::playback::mixer::NoOpVolume;
+use librespot::metadata::audio::AudioItem;
 use librespot::playback::player::{Player, PlayerEvent};
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
 
-use crate::stream::channel_sink::{ChannelSink, SinkEvent};
-use crate::stream::{StreamError, StreamEvent, StreamEventChannel};
+use crate::retry::RetryPolicy;
+use crate::stream::channel_sink::SinkEvent;
+use crate::stream::pool::PlayerPool;
+use crate::stream::{
+    CHANNEL_CAPACITY, Quality, StreamError, StreamEvent, StreamEventChannel, Timeouts,
+};
+use crate::throttle::Throttle;
 use crate::track::Track;
 
 pub struct Stream {
-    player_config: PlayerConfig,
-    session: Session,
+    players: PlayerPool,
+    retry: RetryPolicy,
+    throttle: Throttle,
+    quality: Quality,
+    timeouts: Timeouts,
+    cancel: CancellationToken,
 }
 
 impl Stream {
-    pub fn new(session: Session) -> Self {
-        let config = PlayerConfig {
-            bitrate: Bitrate::Bitrate320,
-            ..Default::default()
-        };
+    /// Cancelling `cancel` stops the player and closes the stream without a `Finished` event.
+    pub fn new(
+        players: PlayerPool,
+        retry: RetryPolicy,
+        throttle: Throttle,
+        quality: Quality,
+        timeouts: Timeouts,
+        cancel: CancellationToken,
+    ) -> Self {
         Stream {
-            player_config: config,
-            session,
+            players,
+            retry,
+            throttle,
+            quality,
+            timeouts,
+            cancel,
         }
     }
 
     pub async fn stream(&self, track: Track) -> Result<StreamEventChannel> {
-        let metadata = track.metadata(&self.session).await?;
-        let (sink, mut channel) = ChannelSink::new(metadata);
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let session = self.players.session();
+        let metadata = track.metadata(session, &self.retry).await?;
+        // The player doesn't tell which file it picks, but it picks it the same way we do
+        let item = self
+            .retry
+            .retry("get audio files", || AudioItem::get_file(session, track.id))
+            .await?;
+        let source = self.quality.select(&item.files).map(|(source, _)| source);
 
-        let player = Player::new(
-            self.player_config.clone(),
-            self.session.clone(),
-            Box::new(NoOpVolume),
-            move || Box::new(sink),
-        );
+        let pooled = self.players.acquire(self.quality.bitrate());
+        let mut channel = pooled.sink.attach(&metadata, source, self.throttle.clone());
+        let (tx, rx) = tokio::sync::mpsc::channel(CHANNEL_CAPACITY);
 
-        tokio::spawn(async move {
-            match tryhard::retry_fn(|| async { Self::load(player.clone(), &track).await })
-                .retries(3)
-                .on_retry(|attempt, _, e| {
-                    let error = format!("{}", e);
-                    let tx = tx.clone();
-                    async move {
-                        tracing::warn!(
-                            "Attempt {} to load track {:?} failed: {}",
-                            attempt,
-                            track.id,
-                            error
-                        );
-                        Self::send_event(&tx, StreamEvent::Retry {
-                            attempt: attempt as usize,
-                            max_attempts: 3,
-                        }).await;
+        let player = pooled.player.clone();
+        let players = self.players.clone();
+        let retry = self.retry.clone();
+        let load_timeout = self.timeouts.load;
+        let cancel = self.cancel.clone();
+        let track_id = track.id;
+
+        let streaming = async move {
+            match tryhard::retry_fn(|| async {
+                retry.until_ready().await;
+                tokio::time::timeout(load_timeout, Self::load(player.clone(), &track))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(StreamError::Timeout(format!(
+                            "the player didn't start playing {:?} within {:?}",
+                            track.id, load_timeout
+                        )))
+                    })
+            })
+            .retries(retry.max_retries)
+            .on_retry(|attempt, next_delay, e| {
+                let error = format!("{}", e);
+                let tx = tx.clone();
+                async move {
+                    tracing::warn!(
+                        "Attempt {} to load track {:?} failed: {}",
+                        attempt,
+                        track.id,
+                        error
+                    );
+                    // No delay means we are giving up
+                    if next_delay.is_some() {
+                        let _ = Self::send_event(
+                            &tx,
+                            StreamEvent::Retry {
+                                attempt: attempt as usize,
+                                max_attempts: retry.max_retries as usize,
+                            },
+                        )
+                        .await;
                     }
-                })
-                .exponential_backoff(Duration::from_secs(10))
-                .max_delay(Duration::from_secs(30))
-                .await
+                }
+            })
+            .custom_backoff(|attempt, e: &StreamError| retry.delay(attempt, e))
+            .await
             {
                 Ok(_) => tracing::info!("Track loaded successfully: {:?}", track.id),
                 Err(e) => {
                     tracing::error!("Failed to load track: {:?}, error: {:?}", track.id, e);
-                    Self::send_event(
-                        &tx,
-                        StreamEvent::Error(StreamError::LoadError(format!(
-                            "Failed to load track: {:?}",
-                            track.id
-                        ))),
-                    )
-                    .await;
-                    return;
+                    let _ = Self::send_event(&tx, StreamEvent::Error(e)).await;
+                    return false;
                 }
             }
 
+            if let Some(source) = source
+                && !Self::send_event(&tx, StreamEvent::Source(source)).await
+            {
+                return false;
+            }
+
             tracing::info!("Streaming track: {:?}", track.id);
 
             while let Some(event) = channel.recv().await {
-                match event {
+                let (event, finished) = match event {
                     SinkEvent::Write {
-                        bytes,
+                        position,
                         total,
                         content,
-                    } => {
-                        Self::send_event(
-                            &tx,
-                            StreamEvent::Write {
-                                bytes,
-                                total,
-                                content,
-                            },
-                        )
-                        .await
-                    }
-                    SinkEvent::Finished => {
-                        Self::send_event(&tx, StreamEvent::Finished).await;
-                        break;
-                    }
+                    } => (
+                        StreamEvent::Write {
+                            position,
+                            total,
+                            content,
+                        },
+                        false,
+                    ),
+                    SinkEvent::Finished => (StreamEvent::Finished, true),
+                };
+                if !Self::send_event(&tx, event).await {
+                    tracing::info!(
+                        "Stream of {:?} was cancelled, stopping the player",
+                        track.id
+                    );
+                    return false;
+                }
+                if finished {
+                    return true;
                 }
             }
+            false
+        };
+
+        tokio::spawn(async move {
+            let finished = tokio::select! {
+                finished = streaming => finished,
+                _ = cancel.cancelled() => {
+                    tracing::info!("Stream of {:?} was aborted, stopping the player", track_id);
+                    false
+                }
+            };
+            // Only a player that got to the end of the track is known to be in a good state. The
+            // streaming future is gone by now, so a sink blocked on its channel is released
+            if finished {
+                players.release(pooled);
+            } else {
+                PlayerPool::discard(pooled);
+            }
         });
 
         Ok(rx)
     }
 
-    async fn load(player: Arc<Player>, track: &Track) -> Result<()> {
+    async fn load(player: Arc<Player>, track: &Track) -> Result<(), StreamError> {
+        // Players are reused, so this only looks at the events about this track
+        let mut events = player.get_player_event_channel();
         player.load(track.id, true, 0);
 
         tracing::info!("Loading track: {:?}", track.id);
         loop {
-            match player.get_player_event_channel().recv().await {
-                Some(PlayerEvent::Playing { .. })
-                | Some(PlayerEvent::TrackChanged { .. })
-                | Some(PlayerEvent::EndOfTrack { .. }) => {
+            match events.recv().await {
+                Some(PlayerEvent::Playing { track_id, .. })
+                | Some(PlayerEvent::EndOfTrack { track_id, .. })
+                    if track_id == track.id =>
+                {
+                    tracing::info!("Player started playing track: {:?}", track.id);
+                    break;
+                }
+                Some(PlayerEvent::TrackChanged { audio_item })
+                    if audio_item.track_id == track.id =>
+                {
                     tracing::info!("Player started playing track: {:?}", track.id);
                     break;
                 }
-                Some(PlayerEvent::Unavailable { .. }) => {
+                Some(PlayerEvent::Unavailable { track_id, .. }) if track_id == track.id => {
                     tracing::info!("Track is unavailable: {:?}", track.id);
-                    return Err(anyhow::anyhow!("Could not load track: {:?}", track.id));
+                    return Err(StreamError::Unavailable(format!("{:?}", track.id)));
+                }
+                None => {
+                    return Err(StreamError::LoadError(format!(
+                        "Player stopped while loading {:?}",
+                        track.id
+                    )));
                 }
                 _ => {
                     // Ignore other events
@@ -139,9 +215,8 @@ impl Stream {
         Ok(())
     }
 
-    async fn send_event(tx: &UnboundedSender<StreamEvent>, event: StreamEvent) {
-        tx.send(event).unwrap_or_else(|e| {
-            tracing::error!("Failed to send event: {:?}", e);
-        });
+    /// Returns false if the receiver is gone, i.e. nobody is interested in the stream anymore.
+    async fn send_event(tx: &Sender<StreamEvent>, event: StreamEvent) -> bool {
+        tx.send(event).await.is_ok()
     }
 }
@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+/// How long a stream may go without making progress before it is considered stuck.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// For the player to start playing a track, or for each request while opening its file.
+    pub load: Duration,
+    /// Between two chunks of audio, once the track is loaded.
+    pub stall: Duration,
+    /// For streaming the whole track once it is loaded, as a multiple of its duration.
+    pub duration_factor: f64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            load: Duration::from_secs(30),
+            stall: Duration::from_secs(60),
+            duration_factor: 4.0,
+        }
+    }
+}
+
+impl Timeouts {
+    /// The longest streaming a track of the given duration may take. Never shorter than the
+    /// stall timeout, short tracks would time out on the first hiccup otherwise.
+    pub fn total(&self, duration_ms: u64) -> Duration {
+        Duration::from_millis(duration_ms)
+            .mul_f64(self.duration_factor)
+            .max(self.stall)
+    }
+}
+
+/// Parses the duration factor, which has to be a positive number.
+pub fn parse_duration_factor(s: &str) -> Result<f64, String> {
+    let factor = s
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("Invalid factor: {}", s))?;
+    if !factor.is_finite() || factor <= 0.0 {
+        return Err(format!("The factor must be a positive number: {}", s));
+    }
+    Ok(factor)
+}
@@ -0,0 +1,244 @@
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use governor::DefaultDirectRateLimiter;
+use governor::Quota;
+use rand::Rng;
+use tokio::time::Instant;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Limits how hard we hit Spotify. It is shared by all the parallel downloads, so the limits
+/// apply to the whole run and not to each track.
+#[derive(Clone, Default)]
+pub struct Throttle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requests: Option<DefaultDirectRateLimiter>,
+    bandwidth: Option<(DefaultDirectRateLimiter, NonZeroU32)>,
+    pause: Option<PauseRange>,
+    started: AtomicBool,
+    backoff: Mutex<Backoff>,
+}
+
+#[derive(Default)]
+struct Backoff {
+    until: Option<Instant>,
+    current: Duration,
+}
+
+impl Throttle {
+    pub fn new(
+        requests_per_minute: Option<NonZeroU32>,
+        bytes_per_second: Option<NonZeroU32>,
+        pause: Option<PauseRange>,
+    ) -> Self {
+        let requests =
+            requests_per_minute.map(|rpm| DefaultDirectRateLimiter::direct(Quota::per_minute(rpm)));
+        let bandwidth = bytes_per_second.map(|bps| {
+            (
+                DefaultDirectRateLimiter::direct(Quota::per_second(bps)),
+                bps,
+            )
+        });
+        Throttle {
+            inner: Arc::new(Inner {
+                requests,
+                bandwidth,
+                pause,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Waits until we are allowed to send another request.
+    pub async fn until_ready(&self) {
+        let until = self.inner.backoff.lock().unwrap().until;
+        if let Some(until) = until {
+            tokio::time::sleep_until(until).await;
+        }
+        if let Some(requests) = &self.inner.requests {
+            requests.until_ready().await;
+        }
+    }
+
+    /// Backs off every request for a while, longer each time we get throttled in a row.
+    pub fn rate_limited(&self) {
+        let mut backoff = self.inner.backoff.lock().unwrap();
+        backoff.current = (backoff.current * 2).clamp(INITIAL_BACKOFF, MAX_BACKOFF);
+        backoff.until = Some(Instant::now() + backoff.current);
+        tracing::warn!(
+            "Spotify is throttling our requests, pausing them for {:?}",
+            backoff.current
+        );
+    }
+
+    pub fn succeeded(&self) {
+        let mut backoff = self.inner.backoff.lock().unwrap();
+        if backoff.until.is_some_and(|until| until <= Instant::now()) {
+            *backoff = Backoff::default();
+        }
+    }
+
+    pub fn limits_bandwidth(&self) -> bool {
+        self.inner.bandwidth.is_some()
+    }
+
+    /// Blocks the current thread until `bytes` can be downloaded within the bandwidth limit.
+    ///
+    /// Meant for the player threads, which don't run on the tokio runtime.
+    pub fn consume_bandwidth_blocking(&self, bytes: u32) {
+        let Some((bandwidth, burst)) = &self.inner.bandwidth else {
+            return;
+        };
+        let mut remaining = bytes;
+        while let Some(chunk) = NonZeroU32::new(remaining.min(burst.get())) {
+            // Can't fail, the chunk is never bigger than the burst size
+            let _ = futures::executor::block_on(bandwidth.until_n_ready(chunk));
+            remaining -= chunk.get();
+        }
+    }
+
+    /// Waits a random amount of time before streaming a track, except for the first one.
+    pub async fn pause_between_tracks(&self) {
+        let Some(pause) = &self.inner.pause else {
+            return;
+        };
+        if !self.inner.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let pause = pause.random();
+        tracing::debug!("Pausing for {:?} before the next track", pause);
+        tokio::time::sleep(pause).await;
+    }
+}
+
+impl std::fmt::Debug for Throttle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Throttle")
+            .field("requests", &self.inner.requests.is_some())
+            .field(
+                "bandwidth",
+                &self.inner.bandwidth.as_ref().map(|(_, bps)| bps),
+            )
+            .field("pause", &self.inner.pause)
+            .finish()
+    }
+}
+
+/// A range of seconds to pause between tracks, written as `5` or `2-10`.
+#[derive(Debug, Clone, Copy)]
+pub struct PauseRange {
+    min: Duration,
+    max: Duration,
+}
+
+impl PauseRange {
+    fn random(&self) -> Duration {
+        if self.min >= self.max {
+            return self.min;
+        }
+        rand::thread_rng().gen_range(self.min..=self.max)
+    }
+}
+
+impl FromStr for PauseRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let seconds = |s: &str| {
+            s.trim()
+                .parse::<f64>()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(|| format!("Invalid number of seconds: {}", s))
+        };
+        let (min, max) = match s.split_once('-') {
+            Some((min, max)) => (seconds(min)?, seconds(max)?),
+            None => (seconds(s)?, seconds(s)?),
+        };
+        if min > max {
+            return Err(format!("Invalid pause range: {}", s));
+        }
+        Ok(PauseRange { min, max })
+    }
+}
+
+/// Parses a rate in bytes per second, optionally with a `K`, `M` or `G` suffix (powers of 1024).
+pub fn parse_rate(s: &str) -> Result<NonZeroU32, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024),
+        Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    let rate = number
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("Invalid rate: {}", s))?
+        * multiplier as f64;
+    if !(1.0..=u32::MAX as f64).contains(&rate) {
+        return Err(format!("Rate out of range: {}", s));
+    }
+    Ok(NonZeroU32::new(rate as u32).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        let rate = |s: &str| parse_rate(s).map(NonZeroU32::get);
+        assert_eq!(rate("1000"), Ok(1000));
+        assert_eq!(rate("500K"), Ok(500 * 1024));
+        assert_eq!(rate("1.5m"), Ok(1024 * 1024 * 3 / 2));
+        assert_eq!(rate(" 2G "), Ok(2 * 1024 * 1024 * 1024));
+        assert!(rate("0").is_err());
+        assert!(rate("0.5").is_err());
+        assert!(rate("4G").is_err());
+        assert!(rate("fast").is_err());
+        assert!(rate("K").is_err());
+    }
+
+    #[test]
+    fn parses_pause_ranges() {
+        let range = |s: &str| s.parse::<PauseRange>().map(|range| (range.min, range.max));
+        assert_eq!(
+            range("5"),
+            Ok((Duration::from_secs(5), Duration::from_secs(5)))
+        );
+        assert_eq!(
+            range("2-10"),
+            Ok((Duration::from_secs(2), Duration::from_secs(10)))
+        );
+        assert_eq!(
+            range("0.5 - 1.5"),
+            Ok((Duration::from_millis(500), Duration::from_millis(1500)))
+        );
+        assert!(range("10-2").is_err());
+        assert!(range("-1").is_err());
+        assert!(range("a-b").is_err());
+    }
+
+    #[test]
+    fn pauses_within_the_range() {
+        let range: PauseRange = "2-3".parse().unwrap();
+        for _ in 0..100 {
+            let pause = range.random();
+            assert!((range.min..=range.max).contains(&pause), "{:?}", pause);
+        }
+        let fixed: PauseRange = "5".parse().unwrap();
+        assert_eq!(fixed.random(), Duration::from_secs(5));
+    }
+}
@@ -1,16 +1,25 @@
 use std::pin::Pin;
 use std::sync::Arc;
 
+use anyhow::Context;
 use anyhow::Result;
 use bytes::Bytes;
-use lazy_static::lazy_static;
+use librespot::core::error::ErrorKind;
 use librespot::core::session::Session;
 use librespot::core::spotify_id::SpotifyId;
 use librespot::metadata::Metadata;
+use librespot::metadata::audio::AudioItem;
 use librespot::metadata::image::Image;
-use regex::Regex;
 
+use crate::encoder::tags::SpotifyIds;
 use crate::encoder::tags::Tags;
+use crate::retry::RetryPolicy;
+use crate::stream::progress::samples_for_duration_ms;
+use crate::uri::LinkResolver;
+use crate::uri::SessionLinkResolver;
+use crate::uri::open_url;
+use crate::uri::parse_uri_or_url;
+use crate::uri::resolve_uri_or_url;
 use crate::utils::clean_invalid_characters;
 
 pub type AsyncFn<T> =
@@ -18,53 +27,78 @@ pub type AsyncFn<T> =
 
 #[async_trait::async_trait]
 trait TrackCollection {
-    async fn get_tracks(&self, session: &Session) -> Vec<Track>;
+    async fn get_tracks(&self, session: &Session, retry: &RetryPolicy) -> Result<Vec<Track>>;
 }
 
-#[tracing::instrument(name = "get_tracks", skip(session), level = "debug")]
-pub async fn get_tracks(spotify_ids: Vec<String>, session: &Session) -> Result<Vec<Track>> {
-    let mut tracks: Vec<Track> = Vec::new();
+pub async fn get_tracks(
+    spotify_ids: Vec<String>,
+    session: &Session,
+    retry: &RetryPolicy,
+) -> Result<Vec<Track>> {
+    let resolver = SessionLinkResolver::new(session.clone());
+    get_tracks_with_resolver(spotify_ids, session, retry, &resolver).await
+}
+
+pub async fn get_tracks_with_resolver(
+    spotify_ids: Vec<String>,
+    session: &Session,
+    retry: &RetryPolicy,
+    resolver: &dyn LinkResolver,
+) -> Result<Vec<Track>> {
+    let groups = get_track_groups_with_resolver(spotify_ids, session, retry, resolver).await?;
+    Ok(groups.into_iter().flat_map(|group| group.tracks).collect())
+}
+
+/// The tracks of a single input.
+#[derive(Clone, Debug)]
+pub struct TrackGroup {
+    /// Whether the tracks are a whole album, in order.
+    pub album: bool,
+    pub tracks: Vec<Track>,
+}
+
+/// Like [`get_tracks`], but keeps the tracks of every input together.
+pub async fn get_track_groups(
+    spotify_ids: Vec<String>,
+    session: &Session,
+    retry: &RetryPolicy,
+) -> Result<Vec<TrackGroup>> {
+    let resolver = SessionLinkResolver::new(session.clone());
+    get_track_groups_with_resolver(spotify_ids, session, retry, &resolver).await
+}
+
+#[tracing::instrument(name = "get_tracks", skip(session, resolver), level = "debug")]
+pub async fn get_track_groups_with_resolver(
+    spotify_ids: Vec<String>,
+    session: &Session,
+    retry: &RetryPolicy,
+    resolver: &dyn LinkResolver,
+) -> Result<Vec<TrackGroup>> {
+    let mut groups: Vec<TrackGroup> = Vec::new();
     for id in spotify_ids {
         tracing::debug!("Getting tracks for: {}", id);
-        let id = parse_uri_or_url(&id).ok_or(anyhow::anyhow!("Invalid track"))?;
+        let id = resolve_uri_or_url(&id, resolver).await?;
         let new_tracks = match id.item_type {
             librespot::core::spotify_id::SpotifyItemType::Track => vec![Track::from_id(id)],
             librespot::core::spotify_id::SpotifyItemType::Episode => vec![Track::from_id(id)],
             librespot::core::spotify_id::SpotifyItemType::Album => {
-                Album::from_id(id).get_tracks(session).await
+                Album::from_id(id).get_tracks(session, retry).await?
             }
             librespot::core::spotify_id::SpotifyItemType::Playlist => {
-                Playlist::from_id(id).get_tracks(session).await
+                Playlist::from_id(id).get_tracks(session, retry).await?
             }
             _ => {
                 tracing::warn!("Unsupported item type: {:?}", id.item_type);
                 vec![]
             }
         };
-        tracks.extend(new_tracks);
+        groups.push(TrackGroup {
+            album: id.item_type == librespot::core::spotify_id::SpotifyItemType::Album,
+            tracks: new_tracks,
+        });
     }
-    tracing::debug!("Got tracks: {:?}", tracks);
-    Ok(tracks)
-}
-
-fn parse_uri_or_url(track: &str) -> Option<SpotifyId> {
-    parse_uri(track).or_else(|| parse_url(track))
-}
-
-fn parse_uri(track_uri: &str) -> Option<SpotifyId> {
-    let res = SpotifyId::from_uri(track_uri);
-    tracing::info!("Parsed URI: {:?}", res);
-    res.ok()
-}
-
-fn parse_url(track_url: &str) -> Option<SpotifyId> {
-    let results = SPOTIFY_URL_REGEX.captures(track_url)?;
-    let uri = format!(
-        "spotify:{}:{}",
-        results.get(1)?.as_str(),
-        results.get(2)?.as_str()
-    );
-    SpotifyId::from_uri(&uri).ok()
+    tracing::debug!("Got tracks: {:?}", groups);
+    Ok(groups)
 }
 
 #[derive(Clone, Debug)]
@@ -72,14 +106,9 @@ pub struct Track {
     pub id: SpotifyId,
 }
 
-lazy_static! {
-    static ref SPOTIFY_URL_REGEX: Regex =
-        Regex::new(r"https://open\.spotify\.com(?:/intl-[a-z]{2})?/(\w+)/([a-zA-Z0-9]+)").unwrap();
-}
-
 impl Track {
     pub fn new(track: &str) -> Result<Self> {
-        let id = parse_uri_or_url(track).ok_or(anyhow::anyhow!("Invalid track"))?;
+        let id = parse_uri_or_url(track)?;
         Ok(Track { id })
     }
 
@@ -87,34 +116,48 @@ impl Track {
         Track { id }
     }
 
-    pub async fn metadata(&self, session: &Session) -> Result<TrackMetadata> {
-        let metadata = librespot::metadata::Track::get(session, &self.id)
+    pub async fn metadata(&self, session: &Session, retry: &RetryPolicy) -> Result<TrackMetadata> {
+        let metadata = retry
+            .retry("get track metadata", || {
+                librespot::metadata::Track::get(session, &self.id)
+            })
             .await
-            .map_err(|_| anyhow::anyhow!("Failed to get metadata"))?;
+            .context("Failed to get metadata")?;
 
         let mut artists = Vec::new();
         for artist in metadata.artists.iter() {
             artists.push(
-                librespot::metadata::Artist::get(session, &artist.id)
+                retry
+                    .retry("get artist metadata", || {
+                        librespot::metadata::Artist::get(session, &artist.id)
+                    })
                     .await
-                    .map_err(|_| anyhow::anyhow!("Failed to get artist"))?,
+                    .context("Failed to get artist")?,
             );
         }
 
-        let album = librespot::metadata::Album::get(session, &metadata.album.id)
+        let album = retry
+            .retry("get album metadata", || {
+                librespot::metadata::Album::get(session, &metadata.album.id)
+            })
             .await
-            .map_err(|_| anyhow::anyhow!("Failed to get album"))?;
+            .context("Failed to get album")?;
 
         let covers = album.covers.clone();
         let session = session.clone();
+        let retry = retry.clone();
 
         let image_retriever: AsyncFn<Bytes> = Arc::new(move || {
             let covers = covers.clone();
             let session = session.clone();
+            let retry = retry.clone();
 
             Box::pin(async move {
                 let cover = covers.first()?;
-                session.spclient().get_image(&cover.id).await.ok()
+                retry
+                    .retry("get cover", || session.spclient().get_image(&cover.id))
+                    .await
+                    .ok()
             })
         });
 
@@ -127,10 +170,99 @@ impl Track {
     }
 }
 
+#[derive(Clone, Debug)]
+pub enum Availability {
+    Available,
+    /// Only playable through another id of the same recording, as Spotify does when relinking.
+    Relinked(SpotifyId),
+    /// Not playable by the current user, but the same recording is available under another id.
+    Alternative {
+        id: SpotifyId,
+        reason: String,
+    },
+    Unavailable(String),
+}
+
+impl Track {
+    /// Checks whether the track can be played by the current user, without loading it.
+    pub async fn availability(
+        &self,
+        session: &Session,
+        retry: &RetryPolicy,
+    ) -> Result<Availability> {
+        let item = match retry
+            .retry("get availability", || AudioItem::get_file(session, self.id))
+            .await
+        {
+            Ok(item) => item,
+            // The same recording may still be available under another id, which only the
+            // track's own metadata lists then
+            Err(e) if e.kind == ErrorKind::Unavailable => {
+                let alternatives = match librespot::metadata::Track::get(session, &self.id).await {
+                    Ok(track) => track.alternatives.0,
+                    Err(e) => {
+                        tracing::debug!("Failed to get alternatives of {:?}: {}", self.id, e);
+                        Vec::new()
+                    }
+                };
+                let alternative =
+                    Self::find_available_alternative(session, self.id, &alternatives).await;
+                return Ok(match alternative {
+                    Some(id) => Availability::Alternative {
+                        id,
+                        reason: e.to_string(),
+                    },
+                    None => Availability::Unavailable(e.to_string()),
+                });
+            }
+            Err(e) => return Err(anyhow::anyhow!("Failed to get availability: {}", e)),
+        };
+
+        let reason = match &item.availability {
+            Ok(()) if !item.files.is_empty() => return Ok(Availability::Available),
+            Ok(()) => "No audio files available".to_string(),
+            Err(reason) => reason.to_string(),
+        };
+
+        let alternatives = item
+            .alternatives
+            .as_ref()
+            .map(|alternatives| alternatives.0.as_slice())
+            .unwrap_or_default();
+        let alternative =
+            Self::find_available_alternative(session, item.track_id, alternatives).await;
+        Ok(match alternative {
+            Some(id) if item.availability.is_ok() => Availability::Relinked(id),
+            Some(id) => Availability::Alternative { id, reason },
+            None => Availability::Unavailable(reason),
+        })
+    }
+
+    async fn find_available_alternative(
+        session: &Session,
+        track_id: SpotifyId,
+        alternatives: &[SpotifyId],
+    ) -> Option<SpotifyId> {
+        for id in alternatives {
+            match AudioItem::get_file(session, *id).await {
+                Ok(alternative)
+                    if alternative.availability.is_ok() && !alternative.files.is_empty() =>
+                {
+                    tracing::info!("Found alternative {:?} for {:?}", id, track_id);
+                    return Some(*id);
+                }
+                Ok(_) => tracing::debug!("Alternative {:?} is not available either", id),
+                Err(e) => tracing::debug!("Failed to get alternative {:?}: {}", id, e),
+            }
+        }
+        None
+    }
+}
+
 #[async_trait::async_trait]
 impl TrackCollection for Track {
-    async fn get_tracks(&self, _session: &Session) -> Vec<Track> {
-        vec![self.clone()]
+    async fn get_tracks(&self, _session: &Session, _retry: &RetryPolicy) -> Result<Vec<Track>> {
+        Ok(vec![self.clone()])
     }
 }
 
@@ -140,7 +272,7 @@ pub struct Album {
 
 impl Album {
     pub fn new(album: &str) -> Result<Self> {
-        let id = parse_uri_or_url(album).ok_or(anyhow::anyhow!("Invalid album"))?;
+        let id = parse_uri_or_url(album)?;
         Ok(Album { id })
     }
 
@@ -155,11 +287,14 @@ impl Album {
 
 #[async_trait::async_trait]
 impl TrackCollection for Album {
-    async fn get_tracks(&self, session: &Session) -> Vec<Track> {
-        let album = librespot::metadata::Album::get(session, &self.id)
+    async fn get_tracks(&self, session: &Session, retry: &RetryPolicy) -> Result<Vec<Track>> {
+        let album = retry
+            .retry("get album", || {
+                librespot::metadata::Album::get(session, &self.id)
+            })
             .await
-            .expect("Failed to get album");
-        album.tracks().map(|track| Track::from_id(*track)).collect()
+            .context("Failed to get album")?;
+        Ok(album.tracks().map(|track| Track::from_id(*track)).collect())
     }
 }
 
@@ -169,7 +304,7 @@ pub struct Playlist {
 
 impl Playlist {
     pub fn new(playlist: &str) -> Result<Self> {
-        let id = parse_uri_or_url(playlist).ok_or(anyhow::anyhow!("Invalid playlist"))?;
+        let id = parse_uri_or_url(playlist)?;
         Ok(Playlist { id })
     }
 
@@ -184,25 +319,56 @@ impl Playlist {
     }
 }
 
+/// A playlist as it is at a given revision.
+#[derive(Clone, Debug)]
+pub struct PlaylistContents {
+    pub name: String,
+    /// Changes whenever the playlist does.
+    pub revision: String,
+    pub tracks: Vec<Track>,
+}
+
+impl Playlist {
+    pub async fn contents(
+        &self,
+        session: &Session,
+        retry: &RetryPolicy,
+    ) -> Result<PlaylistContents> {
+        let playlist = retry
+            .retry("get playlist", || {
+                librespot::metadata::Playlist::get(session, &self.id)
+            })
+            .await
+            .context("Failed to get playlist")?;
+        Ok(PlaylistContents {
+            name: playlist.name().to_string(),
+            revision: playlist.revision.iter().fold(String::new(), |mut hex, byte| {
+                hex.push_str(&format!("{:02x}", byte));
+                hex
+            }),
+            tracks: playlist
+                .tracks()
+                .map(|track| Track::from_id(*track))
+                .collect(),
+        })
+    }
+}
+
 #[async_trait::async_trait]
 impl TrackCollection for Playlist {
-    async fn get_tracks(&self, session: &Session) -> Vec<Track> {
-        let playlist = librespot::metadata::Playlist::get(session, &self.id)
-            .await
-            .expect("Failed to get playlist");
-        playlist
-            .tracks()
-            .map(|track| Track::from_id(*track))
-            .collect()
+    async fn get_tracks(&self, session: &Session, retry: &RetryPolicy) -> Result<Vec<Track>> {
+        Ok(self.contents(session, retry).await?.tracks)
     }
 }
 
 #[derive(Clone)]
 pub struct TrackMetadata {
+    pub id: SpotifyId,
     pub artists: Vec<ArtistMetadata>,
     pub track_name: String,
     pub album: AlbumMetadata,
     pub duration: i32,
+    pub isrc: Option<String>,
     image_retriever: AsyncFn<Bytes>,
 }
 
@@ -219,22 +385,42 @@ impl TrackMetadata {
             .collect();
         let album = AlbumMetadata::from(album);
 
+        let isrc = track
+            .external_ids
+            .iter()
+            .find(|external_id| external_id.external_type.eq_ignore_ascii_case("isrc"))
+            .map(|external_id| external_id.id.clone());
+
         TrackMetadata {
+            id: track.id,
             artists,
             track_name: track.name.clone(),
             album,
             duration: track.duration,
+            isrc,
             image_retriever,
         }
     }
 
-    pub fn approx_size(&self) -> usize {
-        let duration = self.duration / 1000;
-        let sample_rate = 44100;
-        let channels = 2;
-        let bits_per_sample = 32;
-        let bytes_per_sample = bits_per_sample / 8;
-        (duration as usize) * sample_rate * channels * bytes_per_sample
+    /// The length of the track in samples, which is how streaming progress is measured.
+    pub fn total_samples(&self) -> u64 {
+        samples_for_duration_ms(self.duration.max(0) as u64)
+    }
+
+    /// The identifiers linking a file of the track back to Spotify.
+    pub fn spotify_ids(&self) -> SpotifyIds {
+        SpotifyIds {
+            track_uri: self.id.to_uri().ok(),
+            track_id: self.id.to_base62().ok(),
+            album_id: self.album.id.to_base62().ok(),
+            artist_ids: self
+                .artists
+                .iter()
+                .filter_map(|artist| artist.id.to_base62().ok())
+                .collect(),
+            isrc: self.isrc.clone(),
+            source_url: open_url(&self.id),
+        }
     }
 
     pub async fn tags(&self) -> Result<Tags> {
@@ -243,13 +429,16 @@ impl TrackMetadata {
             artists: self.artists.iter().map(|a| a.name.clone()).collect(),
             album_title: self.album.name.clone(),
             album_cover: (self.image_retriever)().await,
+            extra: Vec::new(),
+            gapless: None,
+            chapters: Vec::new(),
         };
         Ok(tags)
     }
 }
 
-impl ToString for TrackMetadata {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for TrackMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if self.artists.len() > 3 {
             let artists_name = self
                 .artists
@@ -258,10 +447,11 @@ impl ToString for TrackMetadata {
                 .map(|artist| artist.name.clone())
                 .collect::<Vec<String>>()
                 .join(", ");
-            return clean_invalid_characters(format!(
-                "{}, ... - {}",
-                artists_name, self.track_name
-            ));
+            return write!(
+                f,
+                "{}",
+                clean_invalid_characters(format!("{}, ... - {}", artists_name, self.track_name))
+            );
         }
 
         let artists_name = self
@@ -270,18 +460,24 @@ impl ToString for TrackMetadata {
             .map(|artist| artist.name.clone())
             .collect::<Vec<String>>()
             .join(", ");
-        clean_invalid_characters(format!("{} - {}", artists_name, self.track_name))
+        write!(
+            f,
+            "{}",
+            clean_invalid_characters(format!("{} - {}", artists_name, self.track_name))
+        )
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct ArtistMetadata {
+    pub id: SpotifyId,
     pub name: String,
 }
 
 impl From<librespot::metadata::Artist> for ArtistMetadata {
     fn from(artist: librespot::metadata::Artist) -> Self {
         ArtistMetadata {
+            id: artist.id,
             name: artist.name.clone(),
         }
     }
@@ -289,14 +485,39 @@ impl From<librespot::metadata::Artist> for ArtistMetadata {
 
 #[derive(Clone, Debug)]
 pub struct AlbumMetadata {
+    pub id: SpotifyId,
     pub name: String,
+    pub artists: Vec<ArtistMetadata>,
     pub cover: Option<Image>,
 }
 
+impl AlbumMetadata {
+    /// The identifiers linking a file of the whole album back to Spotify.
+    pub fn spotify_ids(&self) -> SpotifyIds {
+        SpotifyIds {
+            album_id: self.id.to_base62().ok(),
+            artist_ids: self
+                .artists
+                .iter()
+                .filter_map(|artist| artist.id.to_base62().ok())
+                .collect(),
+            source_url: open_url(&self.id),
+            ..SpotifyIds::default()
+        }
+    }
+}
+
 impl From<librespot::metadata::Album> for AlbumMetadata {
     fn from(album: librespot::metadata::Album) -> Self {
         AlbumMetadata {
+            id: album.id,
             name: album.name.clone(),
+            artists: album
+                .artists
+                .iter()
+                .cloned()
+                .map(ArtistMetadata::from)
+                .collect(),
             cover: album.covers.first().cloned(),
         }
     }
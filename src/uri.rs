@@ -0,0 +1,356 @@
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use url::Url;
+
+const OPEN_HOSTS: [&str; 3] = ["open.spotify.com", "play.spotify.com", "www.spotify.com"];
+const SHORT_LINK_HOSTS: [&str; 2] = ["spotify.link", "spotify.app.link"];
+const ITEM_TYPES: [&str; 6] = ["track", "episode", "album", "playlist", "artist", "show"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum UriError {
+    #[error("\"{0}\" is not a Spotify URI or URL")]
+    NotSpotify(String),
+
+    #[error("Invalid Spotify URI \"{input}\": {reason}")]
+    InvalidUri { input: String, reason: String },
+
+    #[error("Invalid Spotify URL \"{input}\": {reason}")]
+    InvalidUrl { input: String, reason: String },
+
+    #[error("Unsupported Spotify item type \"{item_type}\" in \"{input}\"")]
+    UnsupportedType { input: String, item_type: String },
+
+    #[error("\"{0}\" is a short link and has to be resolved first")]
+    ShortLink(String),
+
+    #[error("Could not resolve short link \"{input}\": {reason}")]
+    Resolve { input: String, reason: String },
+}
+
+/// A parsed user input, either pointing directly to a Spotify item or a short link to one.
+#[derive(Debug, Clone)]
+pub enum SpotifyLink {
+    Id(SpotifyId),
+    ShortLink(Url),
+}
+
+/// Resolves short links (e.g. `https://spotify.link/...`) to the URL they redirect to.
+#[async_trait::async_trait]
+pub trait LinkResolver: Send + Sync {
+    async fn resolve(&self, link: &Url) -> anyhow::Result<String>;
+}
+
+/// Resolves short links through the HTTP client of a librespot session.
+pub struct SessionLinkResolver {
+    session: Session,
+}
+
+impl SessionLinkResolver {
+    pub fn new(session: Session) -> Self {
+        SessionLinkResolver { session }
+    }
+}
+
+#[async_trait::async_trait]
+impl LinkResolver for SessionLinkResolver {
+    async fn resolve(&self, link: &Url) -> anyhow::Result<String> {
+        let request = http::Request::get(link.as_str()).body(Bytes::new())?;
+        let response = self.session.http_client().request_fut(request)?.await?;
+
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(http::header::LOCATION)
+                .ok_or(anyhow::anyhow!("Redirect without a location"))?;
+            return Ok(link.join(location.to_str()?)?.to_string());
+        }
+
+        // Some short links are served as a web page that redirects through javascript
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        let body = String::from_utf8_lossy(&body);
+        let start = body
+            .find("https://open.spotify.com/")
+            .ok_or(anyhow::anyhow!("Unexpected response: {}", status))?;
+        let end = body[start..]
+            .find(|c: char| c == '"' || c == '\'' || c == '<' || c.is_whitespace())
+            .map_or(body.len(), |end| start + end);
+        Ok(body[start..end].to_string())
+    }
+}
+
+/// Parses a Spotify URI or URL into the item it points to.
+///
+/// Short links can't be resolved without network access, use [`resolve_uri_or_url`] for them.
+pub fn parse_uri_or_url(input: &str) -> Result<SpotifyId, UriError> {
+    match parse_link(input)? {
+        SpotifyLink::Id(id) => Ok(id),
+        SpotifyLink::ShortLink(_) => Err(UriError::ShortLink(input.trim().to_string())),
+    }
+}
+
+/// Parses a Spotify URI or URL, resolving short links with the given resolver.
+pub async fn resolve_uri_or_url(
+    input: &str,
+    resolver: &dyn LinkResolver,
+) -> Result<SpotifyId, UriError> {
+    match parse_link(input)? {
+        SpotifyLink::Id(id) => Ok(id),
+        SpotifyLink::ShortLink(link) => {
+            let resolved = resolver
+                .resolve(&link)
+                .await
+                .map_err(|e| UriError::Resolve {
+                    input: input.trim().to_string(),
+                    reason: e.to_string(),
+                })?;
+            tracing::debug!("Resolved short link {} to {}", link, resolved);
+            match parse_link(&resolved)? {
+                SpotifyLink::Id(id) => Ok(id),
+                SpotifyLink::ShortLink(_) => Err(UriError::Resolve {
+                    input: input.trim().to_string(),
+                    reason: format!("redirects to another short link: {}", resolved),
+                }),
+            }
+        }
+    }
+}
+
+/// Parses the supported shapes of Spotify inputs:
+///
+/// - URIs: `spotify:track:id` and legacy `spotify:user:name:playlist:id`
+/// - URLs from `open.spotify.com` and `play.spotify.com`, over `http` or `https`, optionally with
+///   a locale (`/intl-xx/`), as embeds (`/embed/`) or user playlists (`/user/name/playlist/id`)
+/// - Short links from `spotify.link`
+pub fn parse_link(input: &str) -> Result<SpotifyLink, UriError> {
+    let input = input.trim();
+    if input.starts_with("spotify:") {
+        return parse_uri(input).map(SpotifyLink::Id);
+    }
+
+    let with_scheme = if input.contains("://") {
+        input.to_string()
+    } else {
+        format!("https://{}", input)
+    };
+    let url = Url::parse(&with_scheme).map_err(|_| UriError::NotSpotify(input.to_string()))?;
+    let invalid_url = |reason: &str| UriError::InvalidUrl {
+        input: input.to_string(),
+        reason: reason.to_string(),
+    };
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(UriError::NotSpotify(input.to_string()));
+    }
+
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+    if SHORT_LINK_HOSTS.contains(&host.as_str()) {
+        if url.path().trim_matches('/').is_empty() {
+            return Err(invalid_url("missing short link code"));
+        }
+        return Ok(SpotifyLink::ShortLink(url));
+    }
+    if !OPEN_HOSTS.contains(&host.as_str()) {
+        return Err(UriError::NotSpotify(input.to_string()));
+    }
+
+    let mut segments = url
+        .path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+        .peekable();
+
+    segments.next_if(|s| s.starts_with("intl-"));
+    segments.next_if(|s| s.starts_with("embed"));
+    if segments.next_if_eq(&"user").is_some() {
+        segments
+            .next()
+            .ok_or_else(|| invalid_url("missing user name"))?;
+    }
+
+    let item_type = segments
+        .next()
+        .ok_or_else(|| invalid_url("missing item type"))?;
+    let id = segments.next().ok_or_else(|| invalid_url("missing id"))?;
+
+    spotify_id(input, item_type, id).map(SpotifyLink::Id)
+}
+
+/// The `open.spotify.com` URL of an item.
+pub fn open_url(id: &SpotifyId) -> Option<String> {
+    let item_type: &str = id.item_type.into();
+    Some(format!(
+        "https://open.spotify.com/{}/{}",
+        item_type,
+        id.to_base62().ok()?
+    ))
+}
+
+fn parse_uri(input: &str) -> Result<SpotifyId, UriError> {
+    let parts: Vec<&str> = input.split(':').collect();
+    let invalid_uri = |reason: &str| UriError::InvalidUri {
+        input: input.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let (item_type, id) = match parts.as_slice() {
+        ["spotify", "user", _user, item_type, id] => (*item_type, *id),
+        ["spotify", "user", ..] => return Err(invalid_uri("expected spotify:user:name:type:id")),
+        ["spotify", item_type, id] => (*item_type, *id),
+        _ => return Err(invalid_uri("expected spotify:type:id")),
+    };
+
+    spotify_id(input, item_type, id)
+}
+
+fn spotify_id(input: &str, item_type: &str, id: &str) -> Result<SpotifyId, UriError> {
+    if !ITEM_TYPES.contains(&item_type) {
+        return Err(UriError::UnsupportedType {
+            input: input.to_string(),
+            item_type: item_type.to_string(),
+        });
+    }
+
+    let id = SpotifyId::from_uri(&format!("spotify:{}:{}", item_type, id)).map_err(|_| {
+        UriError::InvalidUri {
+            input: input.to_string(),
+            reason: format!("\"{}\" is not a valid id", id),
+        }
+    })?;
+    tracing::debug!("Parsed {} as {:?}", input, id);
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use librespot::core::spotify_id::SpotifyItemType;
+
+    use super::*;
+
+    const TRACK_ID: &str = "4uLU6hMCjMI75M1A2tKUQC";
+    const PLAYLIST_ID: &str = "37i9dQZF1DXcBWIGoYBM5M";
+
+    fn parse_id(input: &str) -> SpotifyId {
+        match parse_link(input) {
+            Ok(SpotifyLink::Id(id)) => id,
+            other => panic!("{} didn't parse to an id: {:?}", input, other),
+        }
+    }
+
+    fn assert_parses(input: &str, item_type: SpotifyItemType, id: &str) {
+        let parsed = parse_id(input);
+        assert_eq!(parsed.item_type, item_type, "{}", input);
+        assert_eq!(parsed.to_base62().unwrap(), id, "{}", input);
+    }
+
+    #[test]
+    fn parses_uris() {
+        let track = format!("spotify:track:{}", TRACK_ID);
+        assert_parses(&track, SpotifyItemType::Track, TRACK_ID);
+        assert_parses(&format!("  {}\n", track), SpotifyItemType::Track, TRACK_ID);
+        assert_parses(
+            &format!("spotify:user:someone:playlist:{}", PLAYLIST_ID),
+            SpotifyItemType::Playlist,
+            PLAYLIST_ID,
+        );
+    }
+
+    #[test]
+    fn parses_urls() {
+        let urls = [
+            format!("https://open.spotify.com/track/{}", TRACK_ID),
+            format!("https://open.spotify.com/track/{}?si=abcdef", TRACK_ID),
+            format!("http://open.spotify.com/track/{}/", TRACK_ID),
+            format!("open.spotify.com/track/{}", TRACK_ID),
+            format!("https://play.spotify.com/track/{}", TRACK_ID),
+            format!("https://open.spotify.com/intl-de/track/{}", TRACK_ID),
+            format!("https://open.spotify.com/embed/track/{}", TRACK_ID),
+            format!("https://OPEN.SPOTIFY.COM/track/{}", TRACK_ID),
+        ];
+        for url in urls {
+            assert_parses(&url, SpotifyItemType::Track, TRACK_ID);
+        }
+        assert_parses(
+            &format!(
+                "https://open.spotify.com/user/someone/playlist/{}",
+                PLAYLIST_ID
+            ),
+            SpotifyItemType::Playlist,
+            PLAYLIST_ID,
+        );
+    }
+
+    #[test]
+    fn recognizes_short_links() {
+        for input in ["https://spotify.link/AbCdEf", "spotify.app.link/AbCdEf"] {
+            assert!(
+                matches!(parse_link(input), Ok(SpotifyLink::ShortLink(_))),
+                "{}",
+                input
+            );
+        }
+        assert!(matches!(
+            parse_link("https://spotify.link/"),
+            Err(UriError::InvalidUrl { .. })
+        ));
+        assert!(matches!(
+            parse_uri_or_url("https://spotify.link/AbCdEf"),
+            Err(UriError::ShortLink(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_other_inputs() {
+        assert!(matches!(
+            parse_link("https://example.com/track/1"),
+            Err(UriError::NotSpotify(_))
+        ));
+        assert!(matches!(
+            parse_link(&format!("ftp://open.spotify.com/track/{}", TRACK_ID)),
+            Err(UriError::NotSpotify(_))
+        ));
+        assert!(matches!(
+            parse_link(&format!("spotify:podcast:{}", TRACK_ID)),
+            Err(UriError::UnsupportedType { .. })
+        ));
+        assert!(matches!(
+            parse_link("spotify:track:not-an-id"),
+            Err(UriError::InvalidUri { .. })
+        ));
+        assert!(matches!(
+            parse_link("spotify:track"),
+            Err(UriError::InvalidUri { .. })
+        ));
+        assert!(matches!(
+            parse_link("https://open.spotify.com/track"),
+            Err(UriError::InvalidUrl { .. })
+        ));
+    }
+
+    struct FixedResolver(&'static str);
+
+    #[async_trait::async_trait]
+    impl LinkResolver for FixedResolver {
+        async fn resolve(&self, _link: &Url) -> anyhow::Result<String> {
+            Ok(self.0.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_short_links() {
+        let resolver = FixedResolver("https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC?si=x");
+        let id = resolve_uri_or_url("https://spotify.link/AbCdEf", &resolver)
+            .await
+            .unwrap();
+        assert_eq!(id.to_base62().unwrap(), TRACK_ID);
+
+        let resolver = FixedResolver("https://spotify.link/Other");
+        assert!(matches!(
+            resolve_uri_or_url("https://spotify.link/AbCdEf", &resolver).await,
+            Err(UriError::Resolve { .. })
+        ));
+    }
+}
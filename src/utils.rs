@@ -1,3 +1,5 @@
+use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
 
 use anyhow::Result;
@@ -14,6 +16,48 @@ where
         .collect()
 }
 
+/// Writes the rows as columns padded to their widest cell, the last column isn't padded.
+pub(crate) fn write_columns<W: Write, const N: usize>(
+    out: &mut W,
+    header: [&str; N],
+    rows: &[[String; N]],
+    right_aligned: &[usize],
+) -> std::io::Result<()> {
+    let mut widths = header.map(|h| h.len());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(console::measure_text_width(cell));
+        }
+    }
+
+    let mut write_row = |row: [&str; N]| -> std::io::Result<()> {
+        let mut line = String::new();
+        for (i, cell) in row.iter().enumerate() {
+            if i == N - 1 {
+                line.push_str(cell);
+            } else if right_aligned.contains(&i) {
+                line.push_str(&format!("{:>w$}  ", cell, w = widths[i]));
+            } else {
+                line.push_str(&format!("{:<w$}  ", cell, w = widths[i]));
+            }
+        }
+        writeln!(out, "{}", line)
+    };
+
+    write_row(header)?;
+    for row in rows {
+        write_row(row.each_ref().map(String::as_str))?;
+    }
+    Ok(())
+}
+
+/// Where a file is written until it is complete, next to its final path.
+pub(crate) fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    PathBuf::from(partial)
+}
+
 const DOT_PATH: &str = ".spotify-dl";
 
 pub(crate) fn get_dot_path() -> Result<PathBuf> {
@@ -23,3 +67,13 @@ pub(crate) fn get_dot_path() -> Result<PathBuf> {
     std::fs::create_dir_all(&path)?;
     Ok(path)
 }
+
+pub(crate) fn format_duration_ms(duration_ms: u64) -> String {
+    let seconds = duration_ms / 1000;
+    let (hours, minutes, seconds) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
@@ -0,0 +1,306 @@
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Serialize;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::encoder::Format;
+use crate::encoder::tags::read_extra_tag;
+use crate::plan::ListFormat;
+use crate::utils::format_duration_ms;
+use crate::utils::write_columns;
+
+/// The tag holding the duration Spotify reports for the track, in milliseconds.
+pub const EXPECTED_DURATION_TAG: &str = "SPOTIFY_DURATION_MS";
+
+/// How much shorter than expected a track can be before it is considered truncated.
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// The audio of a track is shorter than the duration Spotify reports for it.
+#[derive(Debug, thiserror::Error)]
+pub struct Incomplete {
+    pub expected_ms: u64,
+    pub actual_ms: u64,
+}
+
+impl std::fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Incomplete audio, got {} out of {}",
+            format_duration_ms(self.actual_ms),
+            format_duration_ms(self.expected_ms)
+        )
+    }
+}
+
+pub fn check_length(
+    expected_ms: u64,
+    actual_ms: u64,
+    tolerance: Duration,
+) -> Result<(), Incomplete> {
+    if actual_ms + (tolerance.as_millis() as u64) < expected_ms {
+        return Err(Incomplete {
+            expected_ms,
+            actual_ms,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyStatus {
+    Complete,
+    Incomplete,
+    /// The file has no expected duration, it was downloaded by an older version.
+    Unknown,
+    Unreadable,
+}
+
+impl VerifyStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            VerifyStatus::Complete => "complete",
+            VerifyStatus::Incomplete => "incomplete",
+            VerifyStatus::Unknown => "unknown",
+            VerifyStatus::Unreadable => "unreadable",
+        }
+    }
+}
+
+/// The result of checking a single file of the library.
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifiedFile {
+    pub path: PathBuf,
+    pub status: VerifyStatus,
+    pub expected_ms: Option<u64>,
+    pub actual_ms: Option<u64>,
+    pub reason: Option<String>,
+}
+
+impl VerifiedFile {
+    pub fn is_ok(&self) -> bool {
+        matches!(self.status, VerifyStatus::Complete | VerifyStatus::Unknown)
+    }
+}
+
+/// Checks every downloaded file under `directory` against the duration stored in its tags.
+pub fn verify_library(directory: &Path, tolerance: Duration) -> Result<Vec<VerifiedFile>> {
+    let mut files = Vec::new();
+    find_audio_files(directory, &mut files)?;
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(files
+        .into_iter()
+        .map(|(path, format)| verify_file(path, format, tolerance))
+        .collect())
+}
+
+/// Symbolic links to directories aren't followed, so a link back up the tree can't loop.
+pub(crate) fn find_audio_files(directory: &Path, files: &mut Vec<(PathBuf, Format)>) -> Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_audio_files(&path, files)?;
+        } else if let Some(format) = Format::from_path(&path) {
+            files.push((path, format));
+        }
+    }
+    Ok(())
+}
+
+pub fn verify_file(path: PathBuf, format: Format, tolerance: Duration) -> VerifiedFile {
+    let mut verified = VerifiedFile {
+        path,
+        status: VerifyStatus::Unreadable,
+        expected_ms: None,
+        actual_ms: None,
+        reason: None,
+    };
+
+    let actual_ms = match audio_length_ms(&verified.path, format) {
+        Ok(actual_ms) => actual_ms,
+        Err(e) => {
+            verified.reason = Some(e.to_string());
+            return verified;
+        }
+    };
+    verified.actual_ms = Some(actual_ms);
+
+    let expected_ms = match read_extra_tag(&verified.path, EXPECTED_DURATION_TAG, format) {
+        Ok(Some(expected)) => expected.parse::<u64>().ok(),
+        Ok(None) => None,
+        Err(e) => {
+            verified.reason = Some(e.to_string());
+            return verified;
+        }
+    };
+    let Some(expected_ms) = expected_ms else {
+        verified.status = VerifyStatus::Unknown;
+        verified.reason = Some(format!("no {} tag", EXPECTED_DURATION_TAG));
+        return verified;
+    };
+    verified.expected_ms = Some(expected_ms);
+
+    match check_length(expected_ms, actual_ms, tolerance) {
+        Ok(()) => verified.status = VerifyStatus::Complete,
+        Err(e) => {
+            verified.status = VerifyStatus::Incomplete;
+            verified.reason = Some(e.to_string());
+        }
+    }
+    verified
+}
+
+/// The length of the audio in a file, going by the audio itself rather than its tags.
+pub fn audio_length_ms(path: &Path, format: Format) -> Result<u64> {
+    match format {
+        Format::Flac => {
+            let tag = metaflac::Tag::read_from_path(path)?;
+            let info = tag
+                .get_streaminfo()
+                .ok_or(anyhow::anyhow!("Missing FLAC stream info"))?;
+            if info.sample_rate == 0 {
+                return Err(anyhow::anyhow!("Invalid FLAC sample rate"));
+            }
+            Ok(info.total_samples * 1000 / info.sample_rate as u64)
+        }
+        Format::Mp3 => mp3_length_ms(path),
+    }
+}
+
+/// MP3 files don't necessarily know their length, so this goes through all the frames.
+fn mp3_length_ms(path: &Path) -> Result<u64> {
+    let file = std::fs::File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension("mp3");
+    let mut probed = symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions {
+            // Leaves out the encoder delay and padding described by the LAME tag
+            enable_gapless: true,
+            ..Default::default()
+        },
+        &MetadataOptions::default(),
+    )?;
+
+    let sample_rate = probed
+        .format
+        .default_track()
+        .and_then(|track| track.codec_params.sample_rate)
+        .ok_or(anyhow::anyhow!("Missing MP3 sample rate"))?;
+    let mut frames = 0;
+    loop {
+        match probed.format.next_packet() {
+            Ok(packet) => frames += packet.dur,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(frames * 1000 / sample_rate as u64)
+}
+
+pub fn write_verification<W: Write>(
+    files: &[VerifiedFile],
+    format: ListFormat,
+    mut out: W,
+) -> Result<()> {
+    match format {
+        ListFormat::Table => write_table(files, &mut out)?,
+        ListFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, files)?;
+            writeln!(out)?;
+        }
+        ListFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for file in files {
+                writer.serialize(file)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn write_table<W: Write>(files: &[VerifiedFile], out: &mut W) -> Result<()> {
+    let length = |ms: Option<u64>| ms.map(format_duration_ms).unwrap_or_default();
+    let rows: Vec<[String; 4]> = files
+        .iter()
+        .map(|file| {
+            let status = match (&file.reason, file.status) {
+                (Some(reason), VerifyStatus::Unreadable) => {
+                    format!("{} ({})", file.status.as_str(), reason)
+                }
+                _ => file.status.as_str().to_string(),
+            };
+            [
+                status,
+                length(file.actual_ms),
+                length(file.expected_ms),
+                file.path.display().to_string(),
+            ]
+        })
+        .collect();
+    write_columns(
+        out,
+        ["STATUS", "LENGTH", "EXPECTED", "PATH"],
+        &rows,
+        &[1, 2],
+    )?;
+
+    let count = |status: VerifyStatus| files.iter().filter(|f| f.status == status).count();
+    writeln!(
+        out,
+        "\n{} files, {} complete, {} incomplete, {} unknown, {} unreadable",
+        files.len(),
+        count(VerifyStatus::Complete),
+        count(VerifyStatus::Incomplete),
+        count(VerifyStatus::Unknown),
+        count(VerifyStatus::Unreadable),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_length_allows_the_tolerance() {
+        let tolerance = Duration::from_secs(2);
+        assert!(check_length(180_000, 180_000, tolerance).is_ok());
+        assert!(check_length(180_000, 178_000, tolerance).is_ok());
+        assert!(check_length(180_000, 181_000, tolerance).is_ok());
+        let error = check_length(180_000, 177_999, tolerance).unwrap_err();
+        assert_eq!(error.expected_ms, 180_000);
+        assert_eq!(error.actual_ms, 177_999);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn find_audio_files_does_not_follow_directory_links() {
+        let directory = tempfile::tempdir().unwrap();
+        let album = directory.path().join("album");
+        std::fs::create_dir(&album).unwrap();
+        std::fs::write(album.join("track.flac"), b"").unwrap();
+        std::fs::write(album.join("cover.jpg"), b"").unwrap();
+        std::os::unix::fs::symlink(directory.path(), album.join("loop")).unwrap();
+
+        let mut files = Vec::new();
+        find_audio_files(directory.path(), &mut files).unwrap();
+
+        assert_eq!(files, vec![(album.join("track.flac"), Format::Flac)]);
+    }
+}
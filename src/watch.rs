@@ -0,0 +1,613 @@
+//! Watching playlists for new tracks.
+//!
+//! Every playlist is checked on an interval against the snapshot taken the last time, kept in
+//! the dot directory. New tracks are downloaded, removed ones are kept, archived or deleted,
+//! and an M3U playlist is written next to the files with the tracks in playlist order.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Result;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use librespot::core::spotify_id::SpotifyItemType;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::download::DownloadOptions;
+use crate::download::Downloader;
+use crate::report::TrackStatus;
+use crate::shutdown::Shutdown;
+use crate::track::Playlist;
+use crate::track::PlaylistContents;
+use crate::track::Track;
+use crate::track::TrackGroup;
+use crate::uri::SessionLinkResolver;
+use crate::uri::resolve_uri_or_url;
+use crate::utils::clean_invalid_characters;
+use crate::utils::get_dot_path;
+
+/// Where removed tracks are moved to with [`Removal::Archive`], under the destination.
+const ARCHIVE_DIR: &str = "archive";
+
+/// What to do with the files of tracks removed from a playlist.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Removal {
+    Keep,
+    /// Moved to `archive/<playlist>` in the destination.
+    Archive,
+    Delete,
+}
+
+impl FromStr for Removal {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(Removal::Keep),
+            "archive" => Ok(Removal::Archive),
+            "delete" => Ok(Removal::Delete),
+            _ => Err(anyhow::anyhow!(
+                "Unsupported removal, use keep, archive or delete"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// How long to wait between two checks of the playlists.
+    pub interval: Duration,
+    pub removed: Removal,
+    /// Check the playlists a single time instead of on an interval.
+    pub once: bool,
+}
+
+/// A playlist as it was the last time it was checked.
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    uri: String,
+    name: String,
+    revision: String,
+    /// Where the tracks were downloaded, a snapshot taken for another one is ignored.
+    destination: PathBuf,
+    tracks: Vec<WatchedTrack>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WatchedTrack {
+    uri: String,
+    /// Set once the track has been through a download.
+    status: Option<TrackStatus>,
+    path: Option<PathBuf>,
+}
+
+impl WatchedTrack {
+    /// Whether the track still has to be downloaded. Unavailable tracks aren't tried again.
+    fn is_pending(&self) -> bool {
+        matches!(
+            self.status,
+            None | Some(TrackStatus::Failed) | Some(TrackStatus::Cancelled)
+        )
+    }
+
+    /// The file of the track, if it has one.
+    fn file(&self) -> Option<&Path> {
+        match self.status {
+            Some(TrackStatus::Downloaded) | Some(TrackStatus::Skipped) => self.path.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+pub struct Watcher {
+    session: Session,
+    options: DownloadOptions,
+    watch: WatchOptions,
+    shutdown: Shutdown,
+    snapshots: PathBuf,
+}
+
+impl Watcher {
+    pub fn new(
+        session: Session,
+        mut options: DownloadOptions,
+        watch: WatchOptions,
+    ) -> Result<Self> {
+        // Snapshots are tied to the destination, which has to be the same wherever we run from
+        options.destination = std::fs::canonicalize(&options.destination)?;
+        let snapshots = get_dot_path()?.join("watch");
+        std::fs::create_dir_all(&snapshots)?;
+        Ok(Watcher {
+            session,
+            options,
+            watch,
+            shutdown: Shutdown::new(),
+            snapshots,
+        })
+    }
+
+    /// Lets `shutdown` stop watching, after the downloads in progress.
+    pub fn with_shutdown(self, shutdown: Shutdown) -> Self {
+        Watcher { shutdown, ..self }
+    }
+
+    /// Checks the playlists until stopped, or once with [`WatchOptions::once`]. A playlist that
+    /// fails to update is tried again on the next check.
+    pub async fn run(&self, playlists: Vec<String>) -> Result<()> {
+        let resolver = SessionLinkResolver::new(self.session.clone());
+        let mut ids = Vec::new();
+        for playlist in playlists {
+            let id = resolve_uri_or_url(&playlist, &resolver).await?;
+            if id.item_type != SpotifyItemType::Playlist {
+                return Err(anyhow::anyhow!("{} is not a playlist", playlist));
+            }
+            ids.push(id);
+        }
+
+        loop {
+            for id in &ids {
+                if self.shutdown.is_stopped() {
+                    return Ok(());
+                }
+                if let Err(e) = self.check(*id).await {
+                    tracing::error!("Failed to update playlist {:?}: {:?}", id, e);
+                    eprintln!(
+                        "{}",
+                        console::style(format!("Failed to update playlist: {}", e)).red()
+                    );
+                }
+            }
+            if self.watch.once {
+                return Ok(());
+            }
+
+            tracing::info!("Checking again in {:?}", self.watch.interval);
+            tokio::select! {
+                _ = tokio::time::sleep(self.watch.interval) => {}
+                _ = self.shutdown.stopped() => return Ok(()),
+            }
+        }
+    }
+
+    async fn check(&self, id: SpotifyId) -> Result<()> {
+        let uri = id.to_uri()?;
+        let playlist = Playlist::from_id(id);
+        let contents = playlist
+            .contents(&self.session, &self.options.retry)
+            .await?;
+        let previous = self
+            .load_snapshot(&uri)?
+            .filter(|snapshot| snapshot.destination == self.options.destination);
+
+        if let Some(previous) = &previous
+            && previous.revision == contents.revision
+            && !previous.tracks.iter().any(WatchedTrack::is_pending)
+        {
+            println!("{}: no changes", contents.name);
+            return Ok(());
+        }
+
+        let mut snapshot = Self::diff(&uri, &contents, previous, &self.options.destination);
+        self.save_snapshot(&snapshot.current)?;
+        println!(
+            "{}: {} new, {} removed",
+            contents.name,
+            snapshot.added,
+            snapshot.removed.len()
+        );
+
+        self.handle_removed(&snapshot.current, &snapshot.removed)?;
+        self.download_pending(&mut snapshot.current).await?;
+        self.save_snapshot(&snapshot.current)?;
+        self.write_playlist_file(&snapshot.current)?;
+        Ok(())
+    }
+
+    /// Lines up the playlist as it is now with what was downloaded for it before.
+    fn diff(
+        uri: &str,
+        contents: &PlaylistContents,
+        previous: Option<Snapshot>,
+        destination: &Path,
+    ) -> Diff {
+        let previous_tracks = previous.map(|snapshot| snapshot.tracks).unwrap_or_default();
+        let known: HashMap<&str, &WatchedTrack> = previous_tracks
+            .iter()
+            .map(|track| (track.uri.as_str(), track))
+            .collect();
+
+        let mut added = 0;
+        let mut tracks = Vec::new();
+        for track in &contents.tracks {
+            let uri = track.id.to_uri().unwrap_or_default();
+            match known.get(uri.as_str()) {
+                Some(known) => tracks.push((*known).clone()),
+                None => {
+                    added += 1;
+                    tracks.push(WatchedTrack {
+                        uri,
+                        status: None,
+                        path: None,
+                    });
+                }
+            }
+        }
+
+        let current_uris: HashSet<&str> = tracks.iter().map(|track| track.uri.as_str()).collect();
+        let removed = previous_tracks
+            .iter()
+            .filter(|track| !current_uris.contains(track.uri.as_str()))
+            .cloned()
+            .collect();
+
+        Diff {
+            current: Snapshot {
+                uri: uri.to_string(),
+                name: contents.name.clone(),
+                revision: contents.revision.clone(),
+                destination: destination.to_path_buf(),
+                tracks,
+            },
+            added,
+            removed,
+        }
+    }
+
+    async fn download_pending(&self, snapshot: &mut Snapshot) -> Result<()> {
+        let mut seen = HashSet::new();
+        let pending: Vec<_> = snapshot
+            .tracks
+            .iter()
+            .filter(|track| track.is_pending() && seen.insert(track.uri.clone()))
+            .filter_map(|track| Track::new(&track.uri).ok())
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let groups = vec![TrackGroup {
+            album: false,
+            tracks: pending,
+        }];
+        let downloader = Downloader::new(self.session.clone()).with_shutdown(self.shutdown.clone());
+        let report = downloader.download(groups, &self.options).await?;
+        report.print_summary(self.options.quality);
+
+        let reports: HashMap<&str, _> = report
+            .tracks
+            .iter()
+            .map(|track| (track.uri.as_str(), track))
+            .collect();
+        for track in snapshot.tracks.iter_mut() {
+            if let Some(report) = reports.get(track.uri.as_str()) {
+                track.status = Some(report.status);
+                track.path = Some(report.path.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies [`WatchOptions::removed`] to the files of the removed tracks, leaving alone the
+    /// ones still used by this or another watched playlist.
+    fn handle_removed(&self, snapshot: &Snapshot, removed: &[WatchedTrack]) -> Result<()> {
+        if self.watch.removed == Removal::Keep {
+            return Ok(());
+        }
+
+        let mut in_use: HashSet<PathBuf> = snapshot
+            .tracks
+            .iter()
+            .filter_map(|track| track.file().map(Path::to_path_buf))
+            .collect();
+        for other in self.other_snapshots(&snapshot.uri)? {
+            in_use.extend(
+                other
+                    .tracks
+                    .iter()
+                    .filter_map(|track| track.file().map(Path::to_path_buf)),
+            );
+        }
+
+        let archive = self
+            .options
+            .destination
+            .join(ARCHIVE_DIR)
+            .join(clean_invalid_characters(&snapshot.name));
+        for path in removed.iter().filter_map(WatchedTrack::file) {
+            if in_use.contains(path) || !path.exists() {
+                continue;
+            }
+            let result = match self.watch.removed {
+                Removal::Archive => std::fs::create_dir_all(&archive).and_then(|_| {
+                    let file_name = path.file_name().unwrap_or_default();
+                    std::fs::rename(path, archive.join(file_name))
+                }),
+                _ => std::fs::remove_file(path),
+            };
+            match result {
+                Ok(()) => tracing::info!("Removed {}", path.display()),
+                Err(e) => tracing::warn!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `<playlist>.m3u8` in the destination with the files of the playlist, in order.
+    fn write_playlist_file(&self, snapshot: &Snapshot) -> Result<()> {
+        let destination = &self.options.destination;
+        let mut content = String::from("#EXTM3U\n");
+        for path in snapshot.tracks.iter().filter_map(WatchedTrack::file) {
+            if !path.exists() {
+                continue;
+            }
+            let path = path.strip_prefix(destination).unwrap_or(path);
+            content.push_str(&path.to_string_lossy());
+            content.push('\n');
+        }
+
+        let name = clean_invalid_characters(&snapshot.name);
+        let path = destination.join(format!("{}.m3u8", name));
+        std::fs::write(&path, content)?;
+        tracing::info!("Wrote playlist {}", path.display());
+        Ok(())
+    }
+
+    fn snapshot_path(&self, uri: &str) -> PathBuf {
+        let id = uri.rsplit(':').next().unwrap_or(uri);
+        self.snapshots.join(id).with_extension("json")
+    }
+
+    fn load_snapshot(&self, uri: &str) -> Result<Option<Snapshot>> {
+        match std::fs::read(self.snapshot_path(uri)) {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The snapshots of the other playlists watched into the same destination.
+    fn other_snapshots(&self, uri: &str) -> Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        for entry in std::fs::read_dir(&self.snapshots)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json")
+                || path == self.snapshot_path(uri)
+            {
+                continue;
+            }
+            match serde_json::from_slice::<Snapshot>(&std::fs::read(&path)?) {
+                Ok(snapshot) if snapshot.destination == self.options.destination => {
+                    snapshots.push(snapshot)
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Ignoring snapshot {}: {}", path.display(), e),
+            }
+        }
+        Ok(snapshots)
+    }
+
+    /// Saves the snapshot, the file is replaced at once so it is never left half written.
+    fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        let path = self.snapshot_path(&snapshot.uri);
+        let temporary = path.with_extension("json.tmp");
+        std::fs::write(&temporary, serde_json::to_vec_pretty(snapshot)?)?;
+        std::fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+}
+
+struct Diff {
+    current: Snapshot,
+    added: usize,
+    removed: Vec<WatchedTrack>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::Format;
+
+    fn track(n: u8) -> Track {
+        Track::new(&format!("spotify:track:{:0>22}", n)).unwrap()
+    }
+
+    fn contents(tracks: &[u8]) -> PlaylistContents {
+        PlaylistContents {
+            name: "Playlist".to_string(),
+            revision: "2".to_string(),
+            tracks: tracks.iter().map(|n| track(*n)).collect(),
+        }
+    }
+
+    fn downloaded(n: u8, path: &Path) -> WatchedTrack {
+        WatchedTrack {
+            uri: track(n).id.to_uri().unwrap(),
+            status: Some(TrackStatus::Downloaded),
+            path: Some(path.to_path_buf()),
+        }
+    }
+
+    fn snapshot(uri: &str, destination: &Path, tracks: Vec<WatchedTrack>) -> Snapshot {
+        Snapshot {
+            uri: uri.to_string(),
+            name: "Playlist".to_string(),
+            revision: "1".to_string(),
+            destination: destination.to_path_buf(),
+            tracks,
+        }
+    }
+
+    fn watcher(destination: &Path, snapshots: &Path, removed: Removal) -> Watcher {
+        Watcher {
+            session: Session::new(Default::default(), None),
+            options: DownloadOptions::new(
+                Some(destination.to_string_lossy().to_string()),
+                1,
+                Format::Flac,
+                false,
+            ),
+            watch: WatchOptions {
+                interval: Duration::from_secs(60),
+                removed,
+                once: true,
+            },
+            shutdown: Shutdown::new(),
+            snapshots: snapshots.to_path_buf(),
+        }
+    }
+
+    #[test]
+    fn diff_finds_added_and_removed_tracks() {
+        let destination = Path::new("/music");
+        let previous = snapshot(
+            "spotify:playlist:1",
+            destination,
+            vec![
+                downloaded(1, Path::new("/music/1.flac")),
+                downloaded(2, Path::new("/music/2.flac")),
+            ],
+        );
+
+        let diff = Watcher::diff(
+            "spotify:playlist:1",
+            &contents(&[3, 1]),
+            Some(previous),
+            destination,
+        );
+
+        assert_eq!(diff.added, 1);
+        let uris: Vec<&str> = diff.current.tracks.iter().map(|t| t.uri.as_str()).collect();
+        assert_eq!(
+            uris,
+            vec![
+                "spotify:track:0000000000000000000003",
+                "spotify:track:0000000000000000000001",
+            ]
+        );
+        assert!(diff.current.tracks[0].is_pending());
+        assert_eq!(diff.current.tracks[1].status, Some(TrackStatus::Downloaded));
+        assert_eq!(diff.current.revision, "2");
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].uri, "spotify:track:0000000000000000000002");
+    }
+
+    #[test]
+    fn diff_keeps_duplicate_tracks() {
+        let destination = Path::new("/music");
+        let previous = snapshot(
+            "spotify:playlist:1",
+            destination,
+            vec![
+                downloaded(1, Path::new("/music/1.flac")),
+                downloaded(1, Path::new("/music/1.flac")),
+            ],
+        );
+
+        // One of the two copies was removed, the track is still in the playlist
+        let diff = Watcher::diff(
+            "spotify:playlist:1",
+            &contents(&[1]),
+            Some(previous),
+            destination,
+        );
+        assert_eq!(diff.added, 0);
+        assert_eq!(diff.current.tracks.len(), 1);
+        assert!(diff.removed.is_empty());
+
+        // Added twice, both copies are new and waiting for the same download
+        let diff = Watcher::diff("spotify:playlist:1", &contents(&[4, 4]), None, destination);
+        assert_eq!(diff.added, 2);
+        assert!(diff.current.tracks.iter().all(WatchedTrack::is_pending));
+        assert!(diff.removed.is_empty());
+    }
+
+    #[test]
+    fn pending_tracks() {
+        let mut track = downloaded(1, Path::new("/music/1.flac"));
+        let pending = |track: &WatchedTrack| track.is_pending();
+        assert!(!pending(&track));
+        track.status = Some(TrackStatus::Skipped);
+        assert!(!pending(&track));
+        track.status = Some(TrackStatus::Unavailable);
+        assert!(!pending(&track));
+        track.status = Some(TrackStatus::Failed);
+        assert!(pending(&track));
+        track.status = Some(TrackStatus::Cancelled);
+        assert!(pending(&track));
+        track.status = None;
+        assert!(pending(&track));
+    }
+
+    #[tokio::test]
+    async fn removed_files_still_in_use_are_kept() {
+        let destination = tempfile::tempdir().unwrap();
+        let snapshots = tempfile::tempdir().unwrap();
+        let file = |name: &str| {
+            let path = destination.path().join(name);
+            std::fs::write(&path, b"audio").unwrap();
+            path
+        };
+        let (removed, shared, duplicate) = (file("1.flac"), file("2.flac"), file("3.flac"));
+
+        let watcher = watcher(destination.path(), snapshots.path(), Removal::Delete);
+        let other = snapshot(
+            "spotify:playlist:2",
+            destination.path(),
+            vec![downloaded(2, &shared)],
+        );
+        watcher.save_snapshot(&other).unwrap();
+        let current = snapshot(
+            "spotify:playlist:1",
+            destination.path(),
+            vec![downloaded(3, &duplicate)],
+        );
+
+        watcher
+            .handle_removed(
+                &current,
+                &[
+                    downloaded(1, &removed),
+                    downloaded(2, &shared),
+                    downloaded(3, &duplicate),
+                ],
+            )
+            .unwrap();
+
+        assert!(!removed.exists());
+        assert!(shared.exists());
+        assert!(duplicate.exists());
+    }
+
+    #[tokio::test]
+    async fn removed_files_are_archived_or_kept() {
+        let destination = tempfile::tempdir().unwrap();
+        let snapshots = tempfile::tempdir().unwrap();
+        let path = destination.path().join("1.flac");
+        std::fs::write(&path, b"audio").unwrap();
+        let current = snapshot("spotify:playlist:1", destination.path(), Vec::new());
+        let removed = [downloaded(1, &path)];
+
+        watcher(destination.path(), snapshots.path(), Removal::Keep)
+            .handle_removed(&current, &removed)
+            .unwrap();
+        assert!(path.exists());
+
+        watcher(destination.path(), snapshots.path(), Removal::Archive)
+            .handle_removed(&current, &removed)
+            .unwrap();
+        assert!(!path.exists());
+        assert!(
+            destination
+                .path()
+                .join(ARCHIVE_DIR)
+                .join("Playlist")
+                .join("1.flac")
+                .exists()
+        );
+    }
+}